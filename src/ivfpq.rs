@@ -1,5 +1,15 @@
-mod ivfpq;
-mod maxheap_wrapper;
-mod primitive_types;
-mod serialization;
-mod db_api;
\ No newline at end of file
+pub mod ivfpq;
+pub mod maxheap_wrapper;
+pub mod primitive_types;
+pub mod serialization;
+pub mod db_api;
+pub mod error;
+pub mod index;
+pub mod filter;
+pub mod aggregation;
+pub mod cluster_graph;
+pub mod metric;
+pub mod quantization;
+pub mod sdc;
+#[cfg(test)]
+pub(crate) mod test_utils;
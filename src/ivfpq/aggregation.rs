@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use ndarray::Array1;
+use super::{
+    ivfpq::IndexParams,
+    primitive_types::{Clusters, Embedding, SearchHit},
+    error::{KathleenError, KathleenResult}
+};
+
+// SET QUERIES
+// "repos similar to all of these": the per-query results of a set of query repos
+// get folded into a single ranked list, smaller distance still means more similar
+
+/// how the per-query results of a set query become a single list
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregation {
+    /// average distance to every query
+    MeanDistance,
+    /// distance to the farthest query, favours repos close to all of them
+    MaxDistance,
+    /// sum of 1 / (RRF_K + rank) over the queries, reported negated as the distance
+    ReciprocalRank,
+    /// a single search with the mean of the query vectors
+    Centroid
+}
+
+/// smoothing constant of reciprocal-rank fusion, as in the original paper
+pub const RRF_K: f64 = 60.;
+
+/// mean of the query vectors, shaped after params
+pub fn centroid(query_vectors: &[Embedding], params: &IndexParams) -> KathleenResult<Embedding> {
+    if query_vectors.is_empty() {
+        return Err(KathleenError::InvalidParams("a set query needs at least one query vector".to_string()));
+    }
+    let mut sum = Array1::<f64>::zeros(params.dim());
+    for qv in query_vectors {
+        if qv.dim() != params.dim() {
+            return Err(KathleenError::DimensionMismatch { expected: params.dim(), got: qv.dim() });
+        }
+        sum += &Array1::from(qv.to_vec());
+    }
+    Embedding::from_base(sum / query_vectors.len() as f64, params)
+}
+
+/// folds one hit list per query into a single one, nearest first.
+/// mean and max expect every candidate to show up in every list, the ones that don't are aggregated
+/// over the lists they are in. centroid lists come from a single query and are passed through
+pub fn combine(per_query: Vec<Vec<SearchHit>>, aggregation: Aggregation) -> Vec<SearchHit> {
+    // id -> (first hit seen, aggregated value, lists it was found in)
+    let mut candidates: HashMap<u32, (SearchHit, f64, usize)> = HashMap::new();
+    for hits in per_query {
+        for (rank, hit) in hits.into_iter().enumerate() {
+            let value = match aggregation {
+                Aggregation::ReciprocalRank => -1. / (RRF_K + rank as f64 + 1.),
+                _ => hit.distance
+            };
+            candidates.entry(hit.id)
+                .and_modify(|(_, aggregated, found)| {
+                    *aggregated = match aggregation {
+                        Aggregation::MaxDistance => aggregated.max(value),
+                        Aggregation::Centroid => aggregated.min(value),
+                        Aggregation::MeanDistance | Aggregation::ReciprocalRank => *aggregated + value
+                    };
+                    *found += 1;
+                })
+                .or_insert((hit, value, 1));
+        }
+    }
+    let mut combined = candidates.into_values()
+        .map(|(hit, aggregated, found)| {
+            let distance = match aggregation {
+                Aggregation::MeanDistance => aggregated / found as f64,
+                _ => aggregated
+            };
+            SearchHit { distance, ..hit }
+        })
+        .collect::<Vec<SearchHit>>();
+    combined.sort_by(|a, b| a.distance.total_cmp(&b.distance).then(a.id.cmp(&b.id)));
+    combined
+}
+
+/// every (id, cluster) found in any of the lists, once
+pub fn entries_of(per_query: &[Vec<SearchHit>]) -> Vec<(u32, Clusters)> {
+    let mut entries = per_query.iter()
+        .flatten()
+        .map(|hit| (hit.id, hit.cluster))
+        .collect::<Vec<(u32, Clusters)>>();
+    entries.sort();
+    entries.dedup();
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(id: u32, distance: f64) -> SearchHit {
+        SearchHit { id, distance, cluster: 0, key: None, metadata: None }
+    }
+
+    fn ids(hits: &[SearchHit]) -> Vec<u32> {
+        hits.iter().map(|hit| hit.id).collect()
+    }
+
+    #[test]
+    fn lists_get_combined() {
+        let per_query = vec![
+            vec![hit(1, 1.), hit(2, 2.), hit(3, 9.)],
+            vec![hit(3, 1.), hit(2, 2.), hit(1, 7.)]
+        ];
+        let mean = combine(per_query.clone(), Aggregation::MeanDistance);
+        assert_eq!(ids(&mean), vec![2, 1, 3]);
+        assert_eq!(mean[0].distance, 2.);
+        assert_eq!(mean[1].distance, 4.);
+
+        let max = combine(per_query.clone(), Aggregation::MaxDistance);
+        assert_eq!(ids(&max), vec![2, 1, 3]);
+        assert_eq!(max[1].distance, 7.);
+
+        // being first for one query beats being second for both
+        let rrf = combine(per_query, Aggregation::ReciprocalRank);
+        assert_eq!(ids(&rrf), vec![1, 3, 2]);
+        assert_eq!(rrf[0].distance, -1. / (RRF_K + 1.) - 1. / (RRF_K + 3.));
+        assert_eq!(rrf[2].distance, -2. / (RRF_K + 2.));
+
+        let single = combine(vec![vec![hit(4, 0.5), hit(5, 1.5)]], Aggregation::Centroid);
+        assert_eq!(ids(&single), vec![4, 5]);
+        assert_eq!(entries_of(&[vec![hit(4, 0.5)], vec![hit(4, 1.), hit(5, 1.)]]), vec![(4, 0), (5, 0)]);
+    }
+
+    #[test]
+    fn centroid_is_the_mean_query() {
+        let params = IndexParams::new(4, 2, 2, 1).unwrap();
+        let a = Embedding::from_base(Array1::from(vec![0., 2., 4., 6.]), &params).unwrap();
+        let b = Embedding::from_base(Array1::from(vec![2., 2., 0., 0.]), &params).unwrap();
+        assert_eq!(centroid(&[a, b], &params).unwrap().to_vec(), vec![1., 2., 2., 3.]);
+        assert!(centroid(&[], &params).is_err());
+    }
+}
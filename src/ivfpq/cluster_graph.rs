@@ -0,0 +1,268 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap}};
+use ndarray::Array1;
+use ordered_float::NotNan;
+use serde::{Serialize, Deserialize};
+use super::{metric::Metric, primitive_types::{Clusters, Codebook, SearchHit}};
+
+// CLUSTER GRAPH
+// undirected graph whose nodes are the IVF clusters. whenever a query coming from one cluster
+// (the one it falls in, or the home cluster of the user asking) finds repos in another,
+// the edge between both gets created or reinforced by how good those repos were.
+// edge weights work as distances: centroids close to each other and clusters queried together
+// often end up near, so shortest weighted paths tell how related two clusters are.
+// the "station" of a few clusters is the one where their shortest paths meet best
+
+/// what is known about a pair of clusters
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Edge {
+    /// number of queries that crossed from one cluster to the other, in either direction
+    pub queries: u32,
+    /// sum of the similarities of those crossings, each between 0 and 1
+    #[serde(default)]
+    pub strength: f64,
+    /// distance between both centroids under the index metric, see centroid_distance
+    pub centroid_distance: f64
+}
+
+impl Edge {
+    /// centroid distance shrunk by how often and how well the clusters were queried together
+    pub fn weight(&self) -> f64 {
+        self.centroid_distance / (1. + self.strength)
+    }
+}
+
+/// how the path distances from every given cluster to a candidate station are weighed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StationCriterion {
+    /// the farthest given cluster decides, nobody is left far behind
+    MinMax,
+    /// the sum of the distances decides, fewer total hops
+    MinSum
+}
+
+/// meeting cluster of a station query and the repos found in it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Station {
+    pub cluster: Clusters,
+    /// max or sum of the path distances to the given clusters, as picked by the criterion
+    pub distance: f64,
+    pub hits: Vec<SearchHit>
+}
+
+/// edges are kept once, under (smaller cluster, bigger cluster)
+pub fn edge_key(a: Clusters, b: Clusters) -> (Clusters, Clusters) {
+    (a.min(b), a.max(b))
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClusterGraph {
+    edges: HashMap<(Clusters, Clusters), Edge>
+}
+
+impl ClusterGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_edges(edges: impl IntoIterator<Item = ((Clusters, Clusters), Edge)>) -> Self {
+        Self {
+            edges: edges.into_iter().map(|((a, b), edge)| (edge_key(a, b), edge)).collect()
+        }
+    }
+
+    pub fn edge(&self, a: Clusters, b: Clusters) -> Option<&Edge> {
+        self.edges.get(&edge_key(a, b))
+    }
+
+    pub fn edges(&self) -> impl Iterator<Item = ((Clusters, Clusters), &Edge)> {
+        self.edges.iter().map(|(key, edge)| (*key, edge))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edges.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.edges.clear();
+    }
+
+    /// a query from cluster `from` found something in cluster `to`, similarity (clamped to 0..=1)
+    /// tells how close that something was. returns the edge as it is now so it can be persisted,
+    /// None if both are the same cluster
+    pub fn record(&mut self, from: Clusters, to: Clusters, similarity: f64, codebook: &Codebook, metric: Metric) -> Option<((Clusters, Clusters), Edge)> {
+        if from == to {
+            return None;
+        }
+        let key = edge_key(from, to);
+        let edge = self.edges.entry(key).or_insert_with(|| Edge {
+            queries: 0,
+            strength: 0.,
+            centroid_distance: centroid_distance(codebook, metric, from, to)
+        });
+        edge.queries = edge.queries.saturating_add(1);
+        if !similarity.is_nan() {
+            edge.strength += similarity.clamp(0., 1.);
+        }
+        Some((key, *edge))
+    }
+
+    /// clusters sharing an edge with cluster, nearest first
+    pub fn neighbours(&self, cluster: Clusters) -> Vec<(Clusters, f64)> {
+        let mut neighbours = self.edges.iter()
+            .filter_map(|(&(a, b), edge)| match (a == cluster, b == cluster) {
+                (true, _) => Some((b, edge.weight())),
+                (_, true) => Some((a, edge.weight())),
+                _ => None
+            })
+            .collect::<Vec<(Clusters, f64)>>();
+        neighbours.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        neighbours
+    }
+
+    /// weighted distance of the shortest path from cluster to every cluster reachable from it,
+    /// together with the cluster that path comes through last (cluster itself for the source)
+    pub fn distances_from(&self, cluster: Clusters) -> HashMap<Clusters, (f64, Clusters)> {
+        let mut adjacency: HashMap<Clusters, Vec<(Clusters, f64)>> = HashMap::new();
+        for (&(a, b), edge) in &self.edges {
+            adjacency.entry(a).or_default().push((b, edge.weight()));
+            adjacency.entry(b).or_default().push((a, edge.weight()));
+        }
+        // dijkstra, weights are never negative
+        let mut reached: HashMap<Clusters, (f64, Clusters)> = HashMap::from([(cluster, (0., cluster))]);
+        let mut frontier = BinaryHeap::from([Reverse((NotNan::default(), cluster))]);
+        while let Some(Reverse((dist, node))) = frontier.pop() {
+            if *dist > reached[&node].0 {
+                continue;
+            }
+            for &(next, weight) in adjacency.get(&node).map(Vec::as_slice).unwrap_or_default() {
+                let through = *dist + weight;
+                if reached.get(&next).is_some_and(|(known, _)| *known <= through) {
+                    continue;
+                }
+                let Ok(through) = NotNan::new(through) else {
+                    continue;
+                };
+                reached.insert(next, (*through, node));
+                frontier.push(Reverse((through, next)));
+            }
+        }
+        reached
+    }
+
+    /// shortest weighted path between two clusters, both included, with its total weight.
+    /// None if no sequence of queries ever linked them
+    pub fn shortest_path(&self, from: Clusters, to: Clusters) -> Option<(f64, Vec<Clusters>)> {
+        let reached = self.distances_from(from);
+        let (dist, _) = *reached.get(&to)?;
+        let mut path = vec![to];
+        let mut node = to;
+        while node != from {
+            node = reached[&node].1;
+            path.push(node);
+        }
+        path.reverse();
+        Some((dist, path))
+    }
+
+    /// cluster reachable from every given one whose path distances to them are best by criterion,
+    /// together with that distance. the given clusters are candidates themselves, ties go to the
+    /// lowest cluster. None if no cluster is reachable from all of them
+    pub fn station(&self, clusters: &[Clusters], criterion: StationCriterion) -> Option<(Clusters, f64)> {
+        let mut scores: Option<HashMap<Clusters, f64>> = None;
+        for cluster in clusters {
+            let reached = self.distances_from(*cluster);
+            scores = Some(match scores {
+                None => reached.into_iter().map(|(node, (dist, _))| (node, dist)).collect(),
+                Some(scores) => scores.into_iter()
+                    .filter_map(|(node, score)| {
+                        let (dist, _) = reached.get(&node)?;
+                        Some((node, match criterion {
+                            StationCriterion::MinMax => score.max(*dist),
+                            StationCriterion::MinSum => score + dist
+                        }))
+                    })
+                    .collect()
+            });
+        }
+        scores?.into_iter()
+            .min_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)))
+    }
+}
+
+/// edge weights have to stay non negative for the shortest paths, -<a, b> doesn't,
+/// so inner product indexes shift it by the larger of <a, a> and <b, b>, which bounds <a, b>
+fn centroid_distance(codebook: &Codebook, metric: Metric, a: Clusters, b: Clusters) -> f64 {
+    let a = Array1::from(codebook[a as usize].to_vec());
+    let b = Array1::from(codebook[b as usize].to_vec());
+    match metric {
+        Metric::InnerProduct => (a.dot(&a).max(b.dot(&b)) - a.dot(&b)).max(0.),
+        metric => metric.distance(a.view(), b.view())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ivfpq::{ivfpq::IndexParams, primitive_types::Embedding};
+
+    // four centroids on a line, 0 - 1 - 2 - 3, one unit apart
+    fn line_codebook() -> Codebook {
+        let params = IndexParams::new(2, 1, 2, 4).unwrap();
+        (0..4)
+            .map(|x| Embedding::from_base(Array1::from(vec![x as f64, 0.]), &params).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn queries_shape_the_graph() {
+        let codebook = line_codebook();
+        let mut graph = ClusterGraph::new();
+        assert_eq!(graph.record(1, 1, 1., &codebook, Metric::L2), None);
+        assert_eq!(graph.record(0, 3, 1., &codebook, Metric::L2), Some(((0, 3), Edge { queries: 1, strength: 1., centroid_distance: 3. })));
+        graph.record(1, 0, 1., &codebook, Metric::L2);
+        graph.record(1, 2, 1., &codebook, Metric::L2);
+        graph.record(2, 1, 1., &codebook, Metric::L2);
+        graph.record(2, 3, 1., &codebook, Metric::L2);
+        assert_eq!(graph.edge(3, 0).unwrap().weight(), 1.5);
+        assert_eq!(graph.neighbours(1), vec![(2, 1. / 3.), (0, 0.5)]);
+
+        // 0 -> 3 directly weighs 1.5, going through 1 and 2 a bit less
+        let (dist, path) = graph.shortest_path(0, 3).unwrap();
+        assert_eq!((dist, path), (0.5 + 1. / 3. + 0.5, vec![0, 1, 2, 3]));
+        // queried together twice, the direct edge wins now
+        graph.record(3, 0, 1., &codebook, Metric::L2);
+        assert_eq!(graph.shortest_path(0, 3).unwrap(), (1., vec![0, 3]));
+        assert_eq!(graph.shortest_path(2, 2).unwrap(), (0., vec![2]));
+        // every crossing counts, its similarity only between 0 and 1
+        graph.record(3, 0, 0., &codebook, Metric::L2);
+        graph.record(3, 0, 7., &codebook, Metric::L2);
+        assert_eq!(graph.edge(0, 3).unwrap(), &Edge { queries: 4, strength: 3., centroid_distance: 3. });
+
+        // the same centroids, weighed by the metric of the index
+        let mut squared = ClusterGraph::new();
+        assert_eq!(squared.record(0, 3, 0., &codebook, Metric::SquaredL2).unwrap().1.centroid_distance, 9.);
+        let mut inner = ClusterGraph::new();
+        // max(<c3, c3>, <c2, c2>) - <c3, c2> = 9 - 6, never negative
+        assert_eq!(inner.record(3, 2, 0., &codebook, Metric::InnerProduct).unwrap().1.centroid_distance, 3.);
+        assert_eq!(inner.record(0, 3, 0., &codebook, Metric::InnerProduct).unwrap().1.centroid_distance, 9.);
+
+        let lonely = ClusterGraph::from_edges([((2, 1), Edge { queries: 0, strength: 0., centroid_distance: 1. })]);
+        assert_eq!(lonely.shortest_path(0, 1), None);
+        assert!(lonely.edge(1, 2).is_some());
+    }
+
+    #[test]
+    fn stations_depend_on_the_criterion() {
+        let edge = |centroid_distance| Edge { queries: 0, strength: 0., centroid_distance };
+        // 3 is a hub equally far from 0, 1 and 2, 0 is close to 1 but far from 2
+        let graph = ClusterGraph::from_edges([
+            ((0, 3), edge(4.)), ((1, 3), edge(4.)), ((2, 3), edge(4.)),
+            ((0, 1), edge(1.)), ((0, 2), edge(6.))
+        ]);
+        assert_eq!(graph.station(&[0, 1, 2], StationCriterion::MinMax), Some((3, 4.)));
+        assert_eq!(graph.station(&[0, 1, 2], StationCriterion::MinSum), Some((0, 7.)));
+        assert_eq!(graph.station(&[2, 2], StationCriterion::MinSum), Some((2, 0.)));
+        assert_eq!(graph.station(&[0, 5], StationCriterion::MinMax), None);
+        assert_eq!(graph.station(&[], StationCriterion::MinMax), None);
+    }
+}
@@ -1,16 +1,34 @@
-use std::{path::Path, marker::PhantomData};
-use super::{primitive_types::{DBResult, Codebook}, 
-            ivfpq::{InvertedIndex, CQ_K_CENTROIDS}
+use std::{collections::HashSet, path::Path, marker::PhantomData, sync::Mutex};
+use super::{primitive_types::{DBResult, Codebook, PqCodebook, Embedding, Clusters, IVListEntry, RepoMetadata, UserProfile}, 
+            ivfpq::{InvertedIndex, IndexParams, Model, AvlWrapper},
+            serialization::{encode_entry, decode_entry},
+            filter::{Filter, fold_case},
+            cluster_graph::{ClusterGraph, Edge},
+            sdc::{SdcTables, ListTables},
+            error::KathleenError
 };
-use rocksdb::{DB, Options};
+use rocksdb::{DB, ColumnFamily, Direction, IteratorMode, Options, WriteBatch};
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
 use serde_cbor;
 
 // DATABASE
-// will store: Inverted Index File with all its entries
-//             Codebook as for subquantizers which will build up to the CQ Codebook
-//             CQ and each subquantizers' states somehow??
+// will store: Inverted Index File, one column family per list with entries keyed by vector id,
+//             each value a binary (id, pq code, cluster) record, see serialization
+//             Environments, one column family per cluster holding the raw vector of every entry
+//             Codebook as for the coarse quantizer (CQ)
+//             PqCodebook as for subquantizers, trained on the CQ residuals
+//             SDC tables derived from both codebooks, one record per list and per subspace, see sdc
+//             CQ state is not stored, the nearest-centroid assigner is rebuilt from the codebook
+//             Counter handing out vector ids
+//             Bidirectional map between external keys ("owner/repo") and vector ids
+//             Repository metadata documents keyed by vector id
+//             Secondary indexes over the documents (language, license, topics, stars), see index_keys
+//             Cluster graph edges keyed by both clusters, see cluster_graph
+//             User profiles keyed by GitHub login
+//             Ids of vectors whose codes were dropped, until they get a new embedding
+//             Schema record: layout version and the params the index was created with
 
-// the ivf will be working in-memory, although it will eventually get flushed to disk
+// the ivf will be working in-memory, every change to it is written through entry by entry
 
 
 
@@ -20,28 +38,273 @@ pub struct Open {}
 // represent database wrapper to make common calls (put, write...)
 pub struct DatabaseWrapper<T>{
     database: DB,
+    // params the database was created with, checked on open
+    params: IndexParams,
+    // serializes read-increment-write cycles of the id counter
+    id_lock: Mutex<()>,
     _open: PhantomData<T>
 }
 
+#[derive(Serialize, Deserialize)]
+struct Schema {
+    version: u32,
+    params: IndexParams
+}
+
+// MIGRATIONS
+// MIGRATIONS[v] upgrades a database from schema version v to v + 1 in place,
+// the schema record is bumped after each step so an interrupted upgrade resumes where it stopped
+pub const SCHEMA_VERSION: u32 = 6;
+
+type Migration = fn(&mut DatabaseWrapper<Open>) -> DBResult<()>;
+
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] = [split_ivf_blob, drop_ivf_params, create_environments, index_documents, weigh_edges, build_sdc_tables];
+
+/// 0 -> 1: the whole index stored under a single key, every list a string wrapped in CBOR,
+/// gets one column family per list. the lists start empty, their codes came without a PQ codebook
+/// to read them with, so every id is marked stale until it gets a new embedding
+fn split_ivf_blob(db: &mut DatabaseWrapper<Open>) -> DBResult<()> {
+    let Some(ivf) = db.database.get(b"ivf")? else {
+        return Ok(())
+    };
+    let lists: Vec<AvlWrapper> = decode(b"ivf", &ivf)?;
+    let mut batch = WriteBatch::default();
+    for (id, entry) in lists.iter().flat_map(|list| list.iter()) {
+        // codes of another geometry mean the index was written with other params
+        if entry.get_code().len() != db.params.m() {
+            return Err(KathleenError::InvalidParams(format!("vector {id} has a code of {} words, not {}", entry.get_code().len(), db.params.m())))
+        }
+        if let Some(word) = entry.get_code().iter().find(|word| **word as usize >= db.params.ks()) {
+            return Err(KathleenError::InvalidParams(format!("vector {id} has code word {word}, not one of {} sub-centroids", db.params.ks())))
+        }
+        batch.put(stale_key(*id), []);
+    }
+    for cluster in 0..db.params.nlist() as Clusters {
+        db.create_cluster_cf(cluster)?;
+    }
+    // version 1 kept the params under their own key
+    batch.put(b"ivf_params", encode(&db.params)?);
+    batch.delete(b"ivf");
+    Ok(db.database.write(batch)?)
+}
+
+/// 1 -> 2: params move into the schema record
+fn drop_ivf_params(db: &mut DatabaseWrapper<Open>) -> DBResult<()> {
+    let mut batch = WriteBatch::default();
+    batch.delete(b"ivf_params");
+    // left behind if the previous step got interrupted
+    batch.delete(b"ivf");
+    Ok(db.database.write(batch)?)
+}
+
+/// 2 -> 3: every cluster gets an environment, entries stored before have no raw vector
+fn create_environments(db: &mut DatabaseWrapper<Open>) -> DBResult<()> {
+    for cluster in 0..db.params.nlist() as Clusters {
+        db.create_cluster_cf(cluster)?;
+    }
+    Ok(())
+}
+
+/// 3 -> 4: documents stored so far get their secondary index entries
+fn index_documents(db: &mut DatabaseWrapper<Open>) -> DBResult<()> {
+    let mut batch = WriteBatch::default();
+    for stored in db.database.iterator(IteratorMode::From(b"doc:", Direction::Forward)) {
+        let (key, document) = stored?;
+        let Some(id) = key.strip_prefix(b"doc:".as_slice()) else {
+            break
+        };
+        let document: RepoMetadata = decode(&key, &document)?;
+        for index_key in index_keys(decode_id(id)?, &document) {
+            batch.put(index_key, []);
+        }
+    }
+    Ok(db.database.write(batch)?)
+}
+
+/// 4 -> 5: edges get a strength, every query recorded so far counted as a perfect match
+fn weigh_edges(db: &mut DatabaseWrapper<Open>) -> DBResult<()> {
+    let graph = db.load_graph()?;
+    db.persist_edges(graph.edges().map(|(clusters, edge)| (clusters, Edge { strength: edge.queries as f64, ..*edge })))
+}
+
+/// 5 -> 6: trained indexes get the SDC tables of their codebooks
+fn build_sdc_tables(db: &mut DatabaseWrapper<Open>) -> DBResult<()> {
+    if db.database.get(b"codebook")?.is_none() || db.database.get(b"pq_codebook")?.is_none() {
+        return Ok(());
+    }
+    let codebook = db.load_codebook(&db.params)?;
+    let pq_codebook = db.load_pq_codebook(&db.params)?;
+    let mut batch = WriteBatch::default();
+    DatabaseWrapper::put_sdc_tables(&mut batch, &SdcTables::build(&codebook, &pq_codebook))?;
+    Ok(db.database.write(batch)?)
+}
+
 fn db_options() -> Options {
     let mut options = Options::default();
     options.create_if_missing(true);
+    options.create_missing_column_families(true);
     options
 }
 
+fn cluster_cf_name(cluster: Clusters) -> String {
+    format!("cluster_{cluster}")
+}
+
+fn env_cf_name(cluster: Clusters) -> String {
+    format!("env_{cluster}")
+}
+
+fn decode_id(key: &[u8]) -> DBResult<u32> {
+    <[u8; 4]>::try_from(key)
+        .map(u32::from_be_bytes)
+        .map_err(|_| KathleenError::Corrupt {
+            key: String::from_utf8_lossy(key).into_owned(),
+            reason: "not a vector id".to_string()
+        })
+}
+
+fn encode<T: Serialize>(value: &T) -> DBResult<Vec<u8>> {
+    serde_cbor::to_vec(value).map_err(|e| KathleenError::Serialization(e.to_string()))
+}
+
+/// a record that doesn't decode is reported along with the key it was read from
+fn decode<T: DeserializeOwned>(key: &[u8], bytes: &[u8]) -> DBResult<T> {
+    serde_cbor::from_slice(bytes).map_err(|e| KathleenError::Corrupt {
+        key: String::from_utf8_lossy(key).into_owned(),
+        reason: e.to_string()
+    })
+}
+
+fn key_to_id_key(key: &str) -> Vec<u8> {
+    [b"key:".as_slice(), key.as_bytes()].concat()
+}
+
+fn id_to_key_key(id: u32) -> Vec<u8> {
+    [b"id:".as_slice(), &id.to_be_bytes()].concat()
+}
+
+fn document_key(id: u32) -> Vec<u8> {
+    [b"doc:".as_slice(), &id.to_be_bytes()].concat()
+}
+
+// secondary index entries are empty values under idx:<field>:<value>\0<id>, text values case folded,
+// stars under idx:stars:<stars><id> so that ranges come out in order
+fn text_index_prefix(field: &str, value: &str) -> Vec<u8> {
+    [b"idx:".as_slice(), field.as_bytes(), b":", fold_case(value).as_bytes(), b"\0"].concat()
+}
+
+const STARS_INDEX_PREFIX: &[u8] = b"idx:stars:";
+
+const EDGE_PREFIX: &[u8] = b"edge:";
+
+const PROFILE_PREFIX: &[u8] = b"user:";
+
+fn profile_key(login: &str) -> Vec<u8> {
+    [PROFILE_PREFIX, login.as_bytes()].concat()
+}
+
+const STALE_PREFIX: &[u8] = b"stale:";
+
+fn stale_key(id: u32) -> Vec<u8> {
+    [STALE_PREFIX, &id.to_be_bytes()].concat()
+}
+
+// SDC tables under sdc:list:<cluster> for the rows of every list and sdc:sub:<j> for every subspace,
+// so none of the records grows with both nlist and m
+const SDC_LIST_PREFIX: &[u8] = b"sdc:list:";
+
+const SDC_SUBSPACE_PREFIX: &[u8] = b"sdc:sub:";
+
+fn sdc_list_key(cluster: Clusters) -> Vec<u8> {
+    [SDC_LIST_PREFIX, &cluster.to_be_bytes()].concat()
+}
+
+fn sdc_subspace_key(j: usize) -> Vec<u8> {
+    [SDC_SUBSPACE_PREFIX, &(j as u32).to_be_bytes()].concat()
+}
+
+/// edge:<smaller cluster><bigger cluster>, both big endian
+fn edge_db_key((a, b): (Clusters, Clusters)) -> Vec<u8> {
+    [EDGE_PREFIX, &a.to_be_bytes(), &b.to_be_bytes()].concat()
+}
+
+fn index_keys(id: u32, document: &RepoMetadata) -> Vec<Vec<u8>> {
+    let text_fields = document.language.iter().map(|language| ("lang", language))
+        .chain(document.license.iter().map(|license| ("license", license)))
+        .chain(document.topics.iter().map(|topic| ("topic", topic)));
+    text_fields
+        .map(|(field, value)| [text_index_prefix(field, value), id.to_be_bytes().to_vec()].concat())
+        .chain([[STARS_INDEX_PREFIX, &document.stars.to_be_bytes(), &id.to_be_bytes()].concat()])
+        .collect()
+}
+
 impl<> DatabaseWrapper<Closed> {
 
-    pub fn open(path: &Path) -> DBResult<DatabaseWrapper<Open>> {
-        let db = DB::open(&db_options(), path)?;
-        Ok(DatabaseWrapper::<Open> {
+    /// a new database is created with params, an existing one has to have been created with the same ones
+    /// and gets migrated to the current schema if it's older
+    pub fn open(path: &Path, params: &IndexParams) -> DBResult<DatabaseWrapper<Open>> {
+        // column families of the lists have to be named when opening
+        let cfs = DB::list_cf(&db_options(), path).unwrap_or_default();
+        let db = DB::open_cf(&db_options(), path, cfs)?;
+        let mut wrapper = DatabaseWrapper::<Open> {
             database: db,
+            params: *params,
+            id_lock: Mutex::new(()),
             _open: PhantomData
-        })
+        };
+        wrapper.check_schema()?;
+        Ok(wrapper)
     }
 }
 
 impl<> DatabaseWrapper<Open> {
 
+    pub fn params(&self) -> &IndexParams {
+        &self.params
+    }
+
+    fn check_schema(&mut self) -> DBResult<()> {
+        let Some(schema) = self.load_schema()? else {
+            self.persist_ivf(&InvertedIndex::empty(self.params))?;
+            return self.persist_schema(SCHEMA_VERSION)
+        };
+        if schema.version > SCHEMA_VERSION {
+            return Err(KathleenError::UnsupportedSchema { found: schema.version, supported: SCHEMA_VERSION })
+        }
+        if schema.params != self.params {
+            return Err(KathleenError::InvalidParams(format!("database was created with {:?}, not {:?}", schema.params, self.params)))
+        }
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(schema.version as usize) {
+            migration(self)?;
+            self.persist_schema(version as u32 + 1)?;
+        }
+        Ok(())
+    }
+
+    /// databases written before the schema record are told apart by their layout
+    fn load_schema(&self) -> DBResult<Option<Schema>> {
+        if let Some(schema) = self.database.get(b"schema")? {
+            return Ok(Some(decode(b"schema", &schema)?))
+        }
+        if let Some(params) = self.database.get(b"ivf_params")? {
+            return Ok(Some(Schema { version: 1, params: decode(b"ivf_params", &params)? }))
+        }
+        // the first layout was InvertedIndex(Vec<AvlWrapper>), a bare array of lists without params,
+        // all there is to check them against is the number of lists
+        if let Some(ivf) = self.database.get(b"ivf")? {
+            let lists: Vec<AvlWrapper> = decode(b"ivf", &ivf)?;
+            if lists.len() != self.params.nlist() {
+                return Err(KathleenError::InvalidParams(format!("database has {} lists, not {}", lists.len(), self.params.nlist())))
+            }
+            return Ok(Some(Schema { version: 0, params: self.params }))
+        }
+        Ok(None)
+    }
+
+    fn persist_schema(&self, version: u32) -> DBResult<()> {
+        Ok(self.database.put(b"schema", encode(&Schema { version, params: self.params })?)?)
+    }
+
     pub fn persist_codebook(&self, codeb: Codebook) -> DBResult<()> {
         // add embs to the database
         // serialize them into byte arrays
@@ -49,74 +312,570 @@ impl<> DatabaseWrapper<Open> {
         let key = b"codebook";
         match self.database.get(key)? {
             Some(codebook) /* Deserialize codebook & add embedding */ => {
-                // deserialize
-                let deserialized_cb: Codebook = serde_cbor::from_slice(&codebook).expect("Deserialization failed: ");
+                // deserialize, a corrupt record just gets overwritten
+                let deserialized_cb: Option<Codebook> = decode(key, &codebook).ok();
 
                 // add embedding if changed
-                if codeb != deserialized_cb {
+                if Some(&codeb) != deserialized_cb.as_ref() {
                     // remove current codebook
                     self.database.delete(key)?;
                     // serialize codebook
-                    let serialized_cb = serde_cbor::to_vec(&codeb).expect("Serialization failed");
+                    let serialized_cb = encode(&codeb)?;
                     self.database.put(key, serialized_cb)?;
                 }
             },
             None /* Create Codebook (OnDisk, create it without looking for changes) */ => {
-                self.database.put(key, serde_cbor::to_vec(&codeb).expect("Serialization failed"))?;
+                self.database.put(key, encode(&codeb)?)?;
             }
         }
         Ok(())
     }
 
     /// this function will be called when first loading the codebook
-    pub fn load_codebook(&self) -> DBResult<Codebook> {
+    /// params shape the empty codebook returned when none has been persisted yet
+    pub fn load_codebook(&self, params: &IndexParams) -> DBResult<Codebook> {
         let key = b"codebook";
         match self.database.get(key)? {
             Some(codebook) /* Deserialize Codebook */ => {
-                Ok(serde_cbor::from_slice(&codebook).expect("Failed Deserializing:"))
+                decode(key, &codebook)
             },
             None /* Create Codebook (InMemory) */ => {
-                Ok([Default::default(); CQ_K_CENTROIDS])
+                Ok(vec![Embedding::zeros(params); params.nlist()])
             }
         }
         
     }
 
-    pub fn persist_ivf(&self, ivf: InvertedIndex) -> DBResult<()> {
+    /// coarse quantizer able to place vectors right after reopening the database,
+    /// untrained unless both codebooks were persisted, there's no encoding anything without the PQ one
+    pub fn load_model(&self) -> DBResult<Model> {
+        let key = b"codebook";
+        match (self.database.get(key)?, self.database.get(b"pq_codebook")?) {
+            (Some(codebook), Some(_)) => Ok(Model::from_codebook(decode(key, &codebook)?, self.params.metric())),
+            _ => Ok(Model::new())
+        }
+    }
+
+    pub fn persist_pq_codebook(&self, pq_codeb: PqCodebook) -> DBResult<()> {
         // same as persist_codebook
-        let key = b"ivf";
+        let key = b"pq_codebook";
         match self.database.get(key)? {
-            Some(db_ivf) /* Deserialize IVF & add embedding */ => {
-                // deserialize
-                let deserialized_ivf: InvertedIndex = serde_cbor::from_slice(&db_ivf).expect("Deserialization failed: ");
-
-                // add entry if changed
-                if ivf != deserialized_ivf {
-                    // remove current ivf
+            Some(pq_codebook) /* Deserialize PQ codebook & replace it if changed */ => {
+                let deserialized_cb: Option<PqCodebook> = decode(key, &pq_codebook).ok();
+                if Some(&pq_codeb) != deserialized_cb.as_ref() {
                     self.database.delete(key)?;
-                    // serialize ivf
-                    let serialized_cb = serde_cbor::to_vec(&ivf).expect("Serialization failed");
+                    let serialized_cb = encode(&pq_codeb)?;
                     self.database.put(key, serialized_cb)?;
-                } 
+                }
             },
-            None /* Create IVF (OnDisk, create it without looking for changes) */ => {
-                self.database.put(key, serde_cbor::to_vec(&ivf).expect("Serialization failed"))?;
+            None /* Create PQ codebook (OnDisk) */ => {
+                self.database.put(key, encode(&pq_codeb)?)?;
             }
         }
         Ok(())
     }
 
-    pub fn load_ivf(&self) -> DBResult<InvertedIndex> {
-        // same as load_codebook
-        let key = b"ivf";
+    /// same as load_codebook, but for the subspace quantizers
+    pub fn load_pq_codebook(&self, params: &IndexParams) -> DBResult<PqCodebook> {
+        let key = b"pq_codebook";
         match self.database.get(key)? {
-            Some(ivf) /* Deserialize IVF */ => {
-                Ok(serde_cbor::from_slice(&ivf).expect("Error Deserializing: "))
+            Some(pq_codebook) /* Deserialize PQ codebook */ => {
+                decode(key, &pq_codebook)
             },
-            None /* Create IVF (InMemory) */ => {
-                Ok(InvertedIndex::empty())
+            None /* Create PQ codebook (InMemory) */ => {
+                Ok(PqCodebook::zeros(params))
+            }
+        }
+    }
+
+    /// only written in the batch of the codebooks they come from, see persist_training
+    fn put_sdc_tables(batch: &mut WriteBatch, tables: &SdcTables) -> DBResult<()> {
+        for cluster in 0..tables.nlist() as Clusters {
+            batch.put(sdc_list_key(cluster), encode(&tables.list(cluster))?);
+        }
+        for j in 0..tables.m() {
+            batch.put(sdc_subspace_key(j), encode(&tables.subspace(j))?);
+        }
+        Ok(())
+    }
+
+    /// None until the index gets trained, every record is checked against the index geometry
+    pub fn load_sdc_tables(&self) -> DBResult<Option<SdcTables>> {
+        if self.database.get(sdc_list_key(0))?.is_none() {
+            return Ok(None);
+        }
+        let (nlist, m, ks) = (self.params.nlist(), self.params.m(), self.params.ks());
+        let lists = (0..nlist as Clusters)
+            .map(|cluster| self.load_sdc_record(&sdc_list_key(cluster), |list: &ListTables| {
+                list.centroids.len() == nlist && list.cross.len() == m && list.cross.iter().all(|row| row.len() == ks)
+            }))
+            .collect::<DBResult<Vec<ListTables>>>()?;
+        let subspaces = (0..m)
+            .map(|j| self.load_sdc_record(&sdc_subspace_key(j), |products: &Vec<Vec<f64>>| {
+                products.len() == ks && products.iter().all(|row| row.len() == ks)
+            }))
+            .collect::<DBResult<Vec<Vec<Vec<f64>>>>>()?;
+        Ok(Some(SdcTables::from_parts(lists, subspaces)))
+    }
+
+    fn load_sdc_record<T: DeserializeOwned>(&self, key: &[u8], fits: impl Fn(&T) -> bool) -> DBResult<T> {
+        let corrupt = |reason: &str| KathleenError::Corrupt { key: String::from_utf8_lossy(key).into_owned(), reason: reason.to_string() };
+        // the records are written in a single batch, a missing one was never written by this crate
+        let record = self.database.get(key)?.ok_or_else(|| corrupt("missing"))?;
+        let record = decode(key, &record)?;
+        match fits(&record) {
+            true => Ok(record),
+            false => Err(corrupt("tables of another geometry"))
+        }
+    }
+
+    /// hands out a vector id that was never given before, even across restarts
+    /// the counter is written before the id is returned, so a crash can only skip ids, never repeat them
+    pub fn next_id(&self) -> DBResult<u32> {
+        let _guard = self.id_lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let id = self.load_id_counter()?;
+        // u32::MAX only marks the counter as spent
+        let next = id.checked_add(1).ok_or(KathleenError::IdsExhausted)?;
+        self.database.put(b"next_id", encode(&next)?)?;
+        Ok(id)
+    }
+
+    /// makes sure the counter never hands out ids below floor,
+    /// used for indexes whose entries were written before the counter existed
+    pub fn seed_ids(&self, floor: u32) -> DBResult<()> {
+        let _guard = self.id_lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if self.load_id_counter()? < floor {
+            self.database.put(b"next_id", encode(&floor)?)?;
+        }
+        Ok(())
+    }
+
+    fn load_id_counter(&self) -> DBResult<u32> {
+        match self.database.get(b"next_id")? {
+            Some(counter) => decode(b"next_id", &counter),
+            None => Ok(0)
+        }
+    }
+
+    /// binds an external key to a vector id, both directions are written in the same batch
+    pub fn map_key(&self, key: &str, id: u32) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        Self::put_key(&mut batch, key, id)?;
+        Ok(self.database.write(batch)?)
+    }
+
+    fn put_key(batch: &mut WriteBatch, key: &str, id: u32) -> DBResult<()> {
+        batch.put(key_to_id_key(key), encode(&id)?);
+        batch.put(id_to_key_key(id), encode(&key)?);
+        Ok(())
+    }
+
+    pub fn id_for_key(&self, key: &str) -> DBResult<Option<u32>> {
+        let db_key = key_to_id_key(key);
+        self.database.get(&db_key)?
+            .map(|id| decode(&db_key, &id))
+            .transpose()
+    }
+
+    pub fn key_for_id(&self, id: u32) -> DBResult<Option<String>> {
+        let db_key = id_to_key_key(id);
+        self.database.get(&db_key)?
+            .map(|key| decode(&db_key, &key))
+            .transpose()
+    }
+
+    /// drops both directions of the mapping, returns the id the key pointed to
+    pub fn unmap_key(&self, key: &str) -> DBResult<Option<u32>> {
+        let id = self.id_for_key(key)?;
+        if let Some(id) = id {
+            let mut batch = WriteBatch::default();
+            batch.delete(key_to_id_key(key));
+            batch.delete(id_to_key_key(id));
+            self.database.write(batch)?;
+        }
+        Ok(id)
+    }
+
+    /// rewrites every list into its column family in a single batch, dropping entries that left it
+    /// along with their raw vectors. meant for bulk changes like training,
+    /// single entries go through the *_entry functions
+    pub fn persist_ivf(&mut self, ivf: &InvertedIndex) -> DBResult<()> {
+        for cluster in 0..ivf.len() {
+            self.create_cluster_cf(cluster as Clusters)?;
+        }
+        let mut batch = WriteBatch::default();
+        self.put_ivf(&mut batch, ivf, &self.load_stale()?)?;
+        Ok(self.database.write(batch)?)
+    }
+
+    /// everything a training changes goes in a single batch, so a crash leaves either the old index or the new one:
+    /// both codebooks and their SDC tables, every list with the raw vectors of its entries, the ids whose codes
+    /// had to be dropped, and the cluster graph and user profiles, which start over
+    pub fn persist_training<'a>(&mut self, ivf: &InvertedIndex, codebook: &Codebook, pq_codebook: &PqCodebook, sdc: &SdcTables,
+                                vectors: impl IntoIterator<Item = (Clusters, u32, &'a Embedding)>, stale: &HashSet<u32>) -> DBResult<()> {
+        for cluster in 0..ivf.len() {
+            self.create_cluster_cf(cluster as Clusters)?;
+        }
+        let mut batch = WriteBatch::default();
+        batch.put(b"codebook", encode(codebook)?);
+        batch.put(b"pq_codebook", encode(pq_codebook)?);
+        Self::put_sdc_tables(&mut batch, sdc)?;
+        self.put_ivf(&mut batch, ivf, stale)?;
+        // written after the lists, which drop the vectors of entries that moved
+        for (cluster, vec_id, raw) in vectors {
+            batch.put_cf(self.env_cf(cluster)?, vec_id.to_be_bytes(), encode(raw)?);
+        }
+        for vec_id in stale {
+            batch.put(stale_key(*vec_id), []);
+        }
+        self.delete_prefix(&mut batch, EDGE_PREFIX)?;
+        self.delete_prefix(&mut batch, PROFILE_PREFIX)?;
+        Ok(self.database.write(batch)?)
+    }
+
+    /// documents of entries that left the index go too, unless they're still located elsewhere or stale
+    fn put_ivf(&self, batch: &mut WriteBatch, ivf: &InvertedIndex, stale: &HashSet<u32>) -> DBResult<()> {
+        let locations = ivf.locations();
+        for (cluster, avl) in ivf.iter().enumerate() {
+            let cf = self.cluster_cf(cluster as Clusters)?;
+            for stored in self.database.iterator_cf(cf, IteratorMode::Start) {
+                let (id, _) = stored?;
+                match decode_id(&id) {
+                    Ok(vec_id) if avl.get(&vec_id).is_some() => continue,
+                    // moved to another list or waiting for a new embedding, its document stays
+                    Ok(vec_id) if locations.contains_key(&vec_id) || stale.contains(&vec_id) => (),
+                    Ok(vec_id) => self.delete_document(batch, vec_id)?,
+                    Err(_) => ()
+                }
+                batch.delete_cf(cf, id);
+            }
+            for (id, entry) in avl.iter() {
+                batch.put_cf(cf, id.to_be_bytes(), encode_entry(*id, entry)?);
+            }
+            let env = self.env_cf(cluster as Clusters)?;
+            for stored in self.database.iterator_cf(env, IteratorMode::Start) {
+                let (id, _) = stored?;
+                if decode_id(&id).map_or(true, |id| avl.get(&id).is_none()) {
+                    batch.delete_cf(env, id);
+                }
             }
         }
+        Ok(())
+    }
+
+    pub fn load_ivf(&self) -> DBResult<InvertedIndex> {
+        let mut ivf = InvertedIndex::empty(self.params);
+        for cluster in 0..self.params.nlist() as Clusters {
+            *ivf.get_cluster_mut(cluster) = self.load_cluster(cluster)?;
+        }
+        Ok(ivf)
+    }
+
+    fn load_cluster(&self, cluster: Clusters) -> DBResult<AvlWrapper> {
+        let mut avl = AvlWrapper::new();
+        let cf = self.cluster_cf(cluster)?;
+        for stored in self.database.iterator_cf(cf, IteratorMode::Start) {
+            let (id, entry) = stored?;
+            let id = decode_id(&id)?;
+            let corrupt = |reason: String| KathleenError::Corrupt {
+                key: format!("{}/{id}", cluster_cf_name(cluster)),
+                reason
+            };
+            let (stored_id, entry) = decode_entry(&entry).map_err(|e| corrupt(e.to_string()))?;
+            if stored_id != id {
+                return Err(corrupt(format!("record holds vector {stored_id}")));
+            }
+            // a list of another geometry would index past the distance tables
+            if entry.get_code().len() != self.params.m() {
+                return Err(corrupt(format!("code of {} words, expected {}", entry.get_code().len(), self.params.m())));
+            }
+            if let Some(word) = entry.get_code().iter().find(|word| **word as usize >= self.params.ks()) {
+                return Err(corrupt(format!("code word {word} out of {} sub-centroids", self.params.ks())));
+            }
+            avl.insert(id, Box::new(entry));
+        }
+        Ok(avl)
+    }
+
+    /// creates both the list and the environment of cluster if missing
+    fn create_cluster_cf(&mut self, cluster: Clusters) -> DBResult<()> {
+        for name in [cluster_cf_name(cluster), env_cf_name(cluster)] {
+            if self.database.cf_handle(&name).is_none() {
+                self.database.create_cf(&name, &Options::default())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn cluster_cf(&self, cluster: Clusters) -> DBResult<&ColumnFamily> {
+        let name = cluster_cf_name(cluster);
+        self.database.cf_handle(&name)
+            .ok_or(KathleenError::NotFound(format!("column family {name}")))
+    }
+
+    /// lets tests make every write touching the environment of cluster fail
+    #[cfg(test)]
+    pub(super) fn drop_environment(&mut self, cluster: Clusters) -> DBResult<()> {
+        Ok(self.database.drop_cf(&env_cf_name(cluster))?)
+    }
+
+    fn env_cf(&self, cluster: Clusters) -> DBResult<&ColumnFamily> {
+        let name = env_cf_name(cluster);
+        self.database.cf_handle(&name)
+            .ok_or(KathleenError::NotFound(format!("column family {name}")))
+    }
+
+    /// stores a single entry in the column family of its list and its raw vector in the cluster environment,
+    /// costs one write however big the index is
+    pub fn persist_entry(&self, cluster: Clusters, vec_id: u32, entry: &IVListEntry, raw: &Embedding) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        self.put_entry(&mut batch, cluster, vec_id, entry, raw)?;
+        Ok(self.database.write(batch)?)
+    }
+
+    fn put_entry(&self, batch: &mut WriteBatch, cluster: Clusters, vec_id: u32, entry: &IVListEntry, raw: &Embedding) -> DBResult<()> {
+        batch.put_cf(self.cluster_cf(cluster)?, vec_id.to_be_bytes(), encode_entry(vec_id, entry)?);
+        batch.put_cf(self.env_cf(cluster)?, vec_id.to_be_bytes(), encode(raw)?);
+        Ok(())
+    }
+
+    /// everything stored about a new repository goes in a single batch:
+    /// its entry, raw vector, external key and metadata document
+    pub fn persist_repo(&self, cluster: Clusters, vec_id: u32, entry: &IVListEntry, raw: &Embedding, key: &str, document: Option<&RepoMetadata>) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        self.put_entry(&mut batch, cluster, vec_id, entry, raw)?;
+        Self::put_key(&mut batch, key, vec_id)?;
+        if let Some(document) = document {
+            Self::put_document(&mut batch, vec_id, document)?;
+        }
+        Ok(self.database.write(batch)?)
+    }
+
+    /// drops the entry together with its raw vector and metadata document
+    pub fn remove_entry(&self, cluster: Clusters, vec_id: u32) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(self.cluster_cf(cluster)?, vec_id.to_be_bytes());
+        batch.delete_cf(self.env_cf(cluster)?, vec_id.to_be_bytes());
+        self.delete_document(&mut batch, vec_id)?;
+        Ok(self.database.write(batch)?)
+    }
+
+    /// ids of the vectors that lost their codes and wait for a new embedding, see split_ivf_blob and persist_training
+    pub fn load_stale(&self) -> DBResult<HashSet<u32>> {
+        let mut ids = HashSet::new();
+        for stored in self.database.iterator(IteratorMode::From(STALE_PREFIX, Direction::Forward)) {
+            let (key, _) = stored?;
+            let Some(id) = key.strip_prefix(STALE_PREFIX) else {
+                break
+            };
+            ids.insert(decode_id(id)?);
+        }
+        Ok(ids)
+    }
+
+    /// stores a new entry for a stale vector and clears its mark in the same batch
+    pub fn restore_entry(&self, cluster: Clusters, vec_id: u32, entry: &IVListEntry, raw: &Embedding) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        self.put_entry(&mut batch, cluster, vec_id, entry, raw)?;
+        batch.delete(stale_key(vec_id));
+        Ok(self.database.write(batch)?)
+    }
+
+    /// drops a stale vector for good together with its metadata document
+    pub fn remove_stale(&self, vec_id: u32) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        batch.delete(stale_key(vec_id));
+        self.delete_document(&mut batch, vec_id)?;
+        Ok(self.database.write(batch)?)
+    }
+
+    /// replaces the document of vec_id, its index entries are swapped in the same batch
+    pub fn persist_document(&self, vec_id: u32, document: &RepoMetadata) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        self.delete_document(&mut batch, vec_id)?;
+        Self::put_document(&mut batch, vec_id, document)?;
+        Ok(self.database.write(batch)?)
+    }
+
+    fn put_document(batch: &mut WriteBatch, vec_id: u32, document: &RepoMetadata) -> DBResult<()> {
+        batch.put(document_key(vec_id), encode(document)?);
+        for index_key in index_keys(vec_id, document) {
+            batch.put(index_key, []);
+        }
+        Ok(())
+    }
+
+    fn delete_document(&self, batch: &mut WriteBatch, vec_id: u32) -> DBResult<()> {
+        if let Some(document) = self.load_document(vec_id)? {
+            for index_key in index_keys(vec_id, &document) {
+                batch.delete(index_key);
+            }
+        }
+        batch.delete(document_key(vec_id));
+        Ok(())
+    }
+
+    /// ids whose documents match filter according to the secondary indexes, None if it can't tell.
+    /// exact when filter.is_indexed(), otherwise a superset of the matches
+    pub fn ids_matching(&self, filter: &Filter) -> DBResult<Option<HashSet<u32>>> {
+        match filter {
+            Filter::Language(language) => self.scan_text_index("lang", language).map(Some),
+            Filter::License(license) => self.scan_text_index("license", license).map(Some),
+            Filter::Topic(topic) => self.scan_text_index("topic", topic).map(Some),
+            Filter::Stars { min, max } => {
+                let from = [STARS_INDEX_PREFIX, &min.unwrap_or(0).to_be_bytes()].concat();
+                let max = max.unwrap_or(u32::MAX);
+                self.scan_index(STARS_INDEX_PREFIX, &from, |stars_and_id| match <[u8; 8]>::try_from(stars_and_id) {
+                    Ok([a, b, c, d, ..]) => Ok(u32::from_be_bytes([a, b, c, d]) <= max),
+                    Err(_) => Err(format!("expected stars and id, got {} bytes", stars_and_id.len()))
+                })
+                    .map(Some)
+            },
+            Filter::And(filters) => {
+                let mut matching: Option<HashSet<u32>> = None;
+                for ids in filters.iter().map(|filter| self.ids_matching(filter)) {
+                    matching = match (matching, ids?) {
+                        (Some(matching), Some(ids)) => Some(matching.intersection(&ids).copied().collect()),
+                        (matching, ids) => matching.or(ids)
+                    };
+                }
+                Ok(matching)
+            },
+            Filter::Or(filters) => {
+                let mut matching = HashSet::new();
+                for filter in filters {
+                    match self.ids_matching(filter)? {
+                        Some(ids) => matching.extend(ids),
+                        None => return Ok(None)
+                    }
+                }
+                Ok(Some(matching))
+            },
+            Filter::Not(_) => Ok(None)
+        }
+    }
+
+    fn scan_text_index(&self, field: &str, value: &str) -> DBResult<HashSet<u32>> {
+        let prefix = text_index_prefix(field, value);
+        self.scan_index(&prefix, &prefix, |_| Ok(true))
+    }
+
+    /// ids of the index entries under prefix, starting at from and stopping as soon as
+    /// keep turns down the rest of a key. keep tells why a key is malformed instead
+    fn scan_index<F: Fn(&[u8]) -> Result<bool, String>>(&self, prefix: &[u8], from: &[u8], keep: F) -> DBResult<HashSet<u32>> {
+        let mut ids = HashSet::new();
+        for stored in self.database.iterator(IteratorMode::From(from, Direction::Forward)) {
+            let (key, _) = stored?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            let corrupt = |reason: String| KathleenError::Corrupt { key: String::from_utf8_lossy(&key).into_owned(), reason };
+            let rest = &key[prefix.len()..];
+            if rest.len() < 4 {
+                return Err(corrupt("no vector id".to_string()));
+            }
+            if !keep(rest).map_err(corrupt)? {
+                break;
+            }
+            ids.insert(decode_id(&key[key.len() - 4..])?);
+        }
+        Ok(ids)
+    }
+
+    pub fn load_document(&self, vec_id: u32) -> DBResult<Option<RepoMetadata>> {
+        let db_key = document_key(vec_id);
+        self.database.get(&db_key)?
+            .map(|document| decode(&db_key, &document))
+            .transpose()
+    }
+
+    /// takes the entry out of one list and into another in the same batch,
+    /// so it's never stored twice nor lost. the raw vector follows it
+    pub fn move_entry(&self, from: Clusters, to: Clusters, vec_id: u32, entry: &IVListEntry, raw: &Embedding) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(self.cluster_cf(from)?, vec_id.to_be_bytes());
+        batch.delete_cf(self.env_cf(from)?, vec_id.to_be_bytes());
+        batch.put_cf(self.cluster_cf(to)?, vec_id.to_be_bytes(), encode_entry(vec_id, entry)?);
+        batch.put_cf(self.env_cf(to)?, vec_id.to_be_bytes(), encode(raw)?);
+        Ok(self.database.write(batch)?)
+    }
+
+    /// raw vectors of entries written through persist_ivf
+    pub fn persist_vectors<'a>(&self, vectors: impl IntoIterator<Item = (Clusters, u32, &'a Embedding)>) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        for (cluster, vec_id, raw) in vectors {
+            batch.put_cf(self.env_cf(cluster)?, vec_id.to_be_bytes(), encode(raw)?);
+        }
+        Ok(self.database.write(batch)?)
+    }
+
+    /// raw vector stored in the environment of cluster, None for entries written before environments existed
+    pub fn load_vector(&self, cluster: Clusters, vec_id: u32) -> DBResult<Option<Embedding>> {
+        let env = self.env_cf(cluster)?;
+        self.database.get_cf(env, vec_id.to_be_bytes())?
+            .map(|raw| decode(format!("{}/{vec_id}", env_cf_name(cluster)).as_bytes(), &raw))
+            .transpose()
+    }
+
+    /// writes the given edges of the cluster graph, replacing whatever was stored for them
+    pub fn persist_edges(&self, edges: impl IntoIterator<Item = ((Clusters, Clusters), Edge)>) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        for (clusters, edge) in edges {
+            batch.put(edge_db_key(clusters), encode(&edge)?);
+        }
+        Ok(self.database.write(batch)?)
+    }
+
+    pub fn load_graph(&self) -> DBResult<ClusterGraph> {
+        let mut edges = Vec::new();
+        for stored in self.database.iterator(IteratorMode::From(EDGE_PREFIX, Direction::Forward)) {
+            let (key, edge) = stored?;
+            if !key.starts_with(EDGE_PREFIX) {
+                break;
+            }
+            let clusters = &key[EDGE_PREFIX.len()..];
+            if clusters.len() != 8 {
+                return Err(KathleenError::Corrupt { key: String::from_utf8_lossy(&key).into_owned(), reason: "expected two clusters".to_string() });
+            }
+            edges.push(((decode_id(&clusters[..4])?, decode_id(&clusters[4..])?), decode(&key, &edge)?));
+        }
+        Ok(ClusterGraph::from_edges(edges))
+    }
+
+    /// forgets every edge, clusters change meaning when the coarse quantizer is retrained
+    pub fn clear_graph(&self) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        self.delete_prefix(&mut batch, EDGE_PREFIX)?;
+        Ok(self.database.write(batch)?)
+    }
+
+    pub fn persist_profile(&self, profile: &UserProfile) -> DBResult<()> {
+        Ok(self.database.put(profile_key(&profile.login), encode(profile)?)?)
+    }
+
+    pub fn load_profile(&self, login: &str) -> DBResult<Option<UserProfile>> {
+        let key = profile_key(login);
+        self.database.get(&key)?
+            .map(|profile| decode(&key, &profile))
+            .transpose()
+    }
+
+    /// forgets every user profile, their home clusters are gone after retraining
+    pub fn clear_profiles(&self) -> DBResult<()> {
+        let mut batch = WriteBatch::default();
+        self.delete_prefix(&mut batch, PROFILE_PREFIX)?;
+        Ok(self.database.write(batch)?)
+    }
+
+    fn delete_prefix(&self, batch: &mut WriteBatch, prefix: &[u8]) -> DBResult<()> {
+        for stored in self.database.iterator(IteratorMode::From(prefix, Direction::Forward)) {
+            let (key, _) = stored?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            batch.delete(key);
+        }
+        Ok(())
     }
 
 
@@ -125,35 +884,408 @@ impl<> DatabaseWrapper<Open> {
 /// must be ran with -- --test-threads=1 or else db lock will only be acquired by one test
 #[cfg(test)]
 mod tests {
-    use crate::ivfpq::{primitive_types::{Embedding, Segment, IVListEntry}, ivfpq::{SEGMENT_DIM, EMBEDDING_M_SEGMENTS, AvlWrapper, CODE_SIZE}};
+    use crate::ivfpq::{primitive_types::Segment, test_utils::{fresh_database, fresh_db, test_params}};
 
     use super::*;
     #[test]
     fn work_with_codebook() {
-        let db = DatabaseWrapper::open(Path::new("./dbre")).expect("Opening failed: ");
-        let mut codebook = db.load_codebook().unwrap();
-        let segment = Segment::new([-1.; SEGMENT_DIM]);
-        codebook[0] = Embedding::new([segment; EMBEDDING_M_SEGMENTS]);
-        codebook[1] = Embedding::new([segment; EMBEDDING_M_SEGMENTS]);
+        let params = test_params();
+        let db = DatabaseWrapper::open(Path::new("./dbre"), &params).expect("Opening failed: ");
+        let mut codebook = db.load_codebook(&params).unwrap();
+        let segment = Segment::new(vec![-1.; params.segment_dim()]);
+        codebook[0] = Embedding::new(vec![segment.clone(); params.m()]);
+        codebook[1] = Embedding::new(vec![segment; params.m()]);
         let cb_clone = codebook.clone();
         db.persist_codebook(codebook).unwrap();
-        assert_eq!(cb_clone, db.load_codebook().unwrap())
+        assert_eq!(cb_clone, db.load_codebook(&params).unwrap())
+    }
+
+    #[test]
+    fn work_with_pq_codebook() {
+        let params = test_params();
+        let db = DatabaseWrapper::open(Path::new("./dbre"), &params).expect("Opening failed: ");
+        let pq_codebook = db.load_pq_codebook(&params).unwrap();
+        assert_eq!((pq_codebook.m(), pq_codebook.ks()), (params.m(), params.ks()));
+        let subspaces = (0..params.m())
+            .map(|j| (0..params.ks()).map(|c| Segment::new(vec![(j * params.ks() + c) as f64; params.segment_dim()])).collect())
+            .collect();
+        let pq_codebook = PqCodebook::new(subspaces);
+        let pq_clone = pq_codebook.clone();
+        db.persist_pq_codebook(pq_codebook).unwrap();
+        assert_eq!(pq_clone, db.load_pq_codebook(&params).unwrap())
+    }
+
+    #[test]
+    fn ids_are_unique_across_threads_and_reopens() {
+        use std::{collections::HashSet, sync::Arc, thread};
+        let (path, params) = (fresh_db("./dbre_ids"), test_params());
+        let db = Arc::new(DatabaseWrapper::open(path, &params).expect("Opening failed: "));
+        db.seed_ids(10).unwrap();
+        let handles = (0..4).map(|_| {
+            let db = Arc::clone(&db);
+            thread::spawn(move || (0..25).map(|_| db.next_id().unwrap()).collect::<Vec<u32>>())
+        }).collect::<Vec<_>>();
+        let ids = handles.into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect::<Vec<u32>>();
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 100);
+        assert!(ids.iter().all(|id| *id >= 10));
+        drop(db);
+
+        let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+        // seeding below the counter leaves it untouched
+        db.seed_ids(0).unwrap();
+        assert_eq!(db.next_id().unwrap(), 110);
+
+        // the last ids, then an error instead of wrapping around to 0
+        db.seed_ids(u32::MAX - 1).unwrap();
+        assert_eq!(db.next_id().unwrap(), u32::MAX - 1);
+        assert!(matches!(db.next_id(), Err(KathleenError::IdsExhausted)));
+        assert!(matches!(db.next_id(), Err(KathleenError::IdsExhausted)));
+    }
+
+    #[test]
+    fn keys_map_both_ways() {
+        let (db, _, _) = fresh_database("./dbre_keys");
+        db.map_key("rust-lang/rust", 7).unwrap();
+        assert_eq!(db.id_for_key("rust-lang/rust").unwrap(), Some(7));
+        assert_eq!(db.key_for_id(7).unwrap(), Some("rust-lang/rust".to_string()));
+        assert_eq!(db.id_for_key("rust-lang/cargo").unwrap(), None);
+
+        assert_eq!(db.unmap_key("rust-lang/rust").unwrap(), Some(7));
+        assert_eq!(db.id_for_key("rust-lang/rust").unwrap(), None);
+        assert_eq!(db.key_for_id(7).unwrap(), None);
+        assert_eq!(db.unmap_key("rust-lang/rust").unwrap(), None);
+    }
+
+    #[test]
+    fn corrupt_records_are_reported() {
+        let (db, path, params) = fresh_database("./dbre_corrupt");
+        db.database.put(b"codebook", b"definitely not cbor").unwrap();
+        assert!(matches!(db.load_codebook(&params), Err(KathleenError::Corrupt { .. })));
+        // a list entry that isn't a binary record
+        db.database.put_cf(db.cluster_cf(1).unwrap(), 9u32.to_be_bytes(), b"\x01\x04").unwrap();
+        assert!(matches!(db.load_ivf(), Err(KathleenError::Corrupt { .. })));
+
+        // unreadable schema records and legacy indexes keep the database from opening
+        db.database.put(b"schema", b"definitely not cbor").unwrap();
+        drop(db);
+        assert!(matches!(DatabaseWrapper::open(path, &params), Err(KathleenError::Corrupt { .. })));
+        let db = DB::open_cf(&db_options(), path, DB::list_cf(&db_options(), path).unwrap()).unwrap();
+        db.delete(b"schema").unwrap();
+        db.put(b"ivf", serde_cbor::to_vec(&"{1: [1, 1];x\n}").unwrap()).unwrap();
+        drop(db);
+        assert!(matches!(DatabaseWrapper::open(path, &params), Err(KathleenError::Corrupt { .. })));
     }
 
     #[test]
     fn work_with_inverted_index() {
-        let db = DatabaseWrapper::open(Path::new("./dbre")).expect("Opening failed: ");
+        let (mut db, path, params) = fresh_database("./dbre_ivf");
         let mut ivf = db.load_ivf().unwrap();
-        let mut avl = AvlWrapper::new();
-        avl.insert(123, Box::new(IVListEntry::new([1; EMBEDDING_M_SEGMENTS], 0)));
-        avl.insert(124, Box::new(IVListEntry::new([1; EMBEDDING_M_SEGMENTS], 1)));
-        ivf.push(avl);
-        let ivf_clone = ivf.clone();
-        db.persist_ivf(ivf).unwrap();
+        ivf.get_cluster_mut(0).insert(123, Box::new(IVListEntry::new(vec![1; params.m()], 0)));
+        ivf.get_cluster_mut(1).insert(124, Box::new(IVListEntry::new(vec![1; params.m()], 1)));
+        db.persist_ivf(&ivf).unwrap();
         let reloaded_ivf = db.load_ivf().unwrap();
         // only visible with -- --nocapture
-        println!("{:?}", ivf_clone);
+        println!("{:?}", ivf);
         println!("{:?}", reloaded_ivf);
-        assert_eq!(ivf_clone, reloaded_ivf);
+        assert_eq!(ivf, reloaded_ivf);
+
+        // entries gone from the in-memory lists are gone from disk too
+        ivf.get_cluster_mut(0).remove(&123);
+        db.persist_ivf(&ivf).unwrap();
+        drop(db);
+        let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+        assert_eq!(ivf, db.load_ivf().unwrap());
+    }
+
+    #[test]
+    fn codes_of_another_geometry_are_corrupt() {
+        let (db, _, params) = fresh_database("./dbre_geometry");
+        let raw = Embedding::zeros(&params);
+        for code in [vec![1; 3], vec![1; 6], vec![1, 8, 1, 1]] {
+            db.persist_entry(2, 7, &IVListEntry::new(code.clone(), 2), &raw).unwrap();
+            assert!(matches!(db.load_ivf(), Err(KathleenError::Corrupt { .. })), "{code:?}");
+        }
+        db.persist_entry(2, 7, &IVListEntry::new(vec![1, 7, 1, 1], 2), &raw).unwrap();
+        assert!(db.load_ivf().is_ok());
+    }
+
+    #[test]
+    fn entries_are_written_one_by_one() {
+        let (db, path, params) = fresh_database("./dbre_entries");
+        let mut ivf = db.load_ivf().unwrap();
+        let entry = IVListEntry::new(vec![2; params.m()], 3);
+        let raw = Embedding::new(vec![Segment::new(vec![0.5; params.segment_dim()]); params.m()]);
+        db.persist_entry(3, 42, &entry, &raw).unwrap();
+        db.persist_entry(5, 43, &IVListEntry::new(vec![4; params.m()], 5), &Embedding::zeros(&params)).unwrap();
+        db.move_entry(3, 6, 42, &IVListEntry::new(vec![2; params.m()], 6), &raw).unwrap();
+        db.remove_entry(5, 43).unwrap();
+        drop(db);
+
+        let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+        ivf.get_cluster_mut(6).insert(42, Box::new(IVListEntry::new(vec![2; params.m()], 6)));
+        assert_eq!(ivf, db.load_ivf().unwrap());
+        assert_eq!(db.load_vector(6, 42).unwrap(), Some(raw));
+        assert_eq!(db.load_vector(3, 42).unwrap(), None);
+        assert_eq!(db.load_vector(5, 43).unwrap(), None);
+    }
+
+    #[test]
+    fn raw_vectors_go_away_with_their_entries() {
+        let (mut db, _, params) = fresh_database("./dbre_env");
+        let mut ivf = db.load_ivf().unwrap();
+        ivf.get_cluster_mut(1).insert(10, Box::new(IVListEntry::new(vec![1; params.m()], 1)));
+        ivf.get_cluster_mut(1).insert(11, Box::new(IVListEntry::new(vec![1; params.m()], 1)));
+        let raw = Embedding::zeros(&params);
+        db.persist_ivf(&ivf).unwrap();
+        db.persist_vectors([(1, 10, &raw), (1, 11, &raw)]).unwrap();
+        assert_eq!(db.load_vector(1, 11).unwrap(), Some(raw.clone()));
+
+        ivf.get_cluster_mut(1).remove(&11);
+        db.persist_ivf(&ivf).unwrap();
+        assert_eq!(db.load_vector(1, 10).unwrap(), Some(raw));
+        assert_eq!(db.load_vector(1, 11).unwrap(), None);
+    }
+
+    #[test]
+    fn repos_are_stored_with_their_documents() {
+        let (db, _, params) = fresh_database("./dbre_docs");
+        let document = RepoMetadata {
+            name: "rust-lang/rust".to_string(),
+            description: Some("Empowering everyone to build reliable and efficient software.".to_string()),
+            language: Some("Rust".to_string()),
+            license: Some("Apache-2.0".to_string()),
+            stars: 90000,
+            topics: vec!["compiler".to_string(), "language".to_string()]
+        };
+        let entry = IVListEntry::new(vec![3; params.m()], 2);
+        db.persist_repo(2, 5, &entry, &Embedding::zeros(&params), "rust-lang/rust", Some(&document)).unwrap();
+        db.persist_repo(2, 6, &entry, &Embedding::zeros(&params), "rust-lang/cargo", None).unwrap();
+        assert_eq!(db.load_document(5).unwrap(), Some(document.clone()));
+        assert_eq!(db.load_document(6).unwrap(), None);
+        assert_eq!(db.id_for_key("rust-lang/rust").unwrap(), Some(5));
+        assert_eq!(db.load_vector(2, 5).unwrap(), Some(Embedding::zeros(&params)));
+        assert_eq!(db.load_ivf().unwrap().get_cluster(2).len(), 2);
+
+        let renamed = RepoMetadata { name: "rust-lang/rustc".to_string(), ..document };
+        db.persist_document(6, &renamed).unwrap();
+        assert_eq!(db.load_document(6).unwrap(), Some(renamed));
+        db.remove_entry(2, 5).unwrap();
+        assert_eq!(db.load_document(5).unwrap(), None);
+    }
+
+    fn repo(name: &str, language: &str, license: &str, stars: u32) -> RepoMetadata {
+        RepoMetadata {
+            name: name.to_string(),
+            language: Some(language.to_string()),
+            license: Some(license.to_string()),
+            stars,
+            ..RepoMetadata::default()
+        }
+    }
+
+    #[test]
+    fn secondary_indexes_follow_documents() {
+        let (db, _, params) = fresh_database("./dbre_filters");
+        let entry = IVListEntry::new(vec![0; params.m()], 0);
+        let repos = [
+            repo("rust-lang/rust", "Rust", "MIT", 90000),
+            repo("tokio-rs/tokio", "Rust", "MIT", 100),
+            repo("golang/go", "Go", "BSD-3-Clause", 110000),
+            repo("serde-rs/serde", "Rust", "Apache-2.0", 8000)
+        ];
+        for (id, repo) in repos.iter().enumerate() {
+            db.persist_repo(0, id as u32, &entry, &Embedding::zeros(&params), &repo.name, Some(repo)).unwrap();
+        }
+        let ids = |filter: Filter| db.ids_matching(&filter).unwrap().map(|ids| {
+            let mut ids = ids.into_iter().collect::<Vec<u32>>();
+            ids.sort();
+            ids
+        });
+        assert_eq!(ids(Filter::Language("rust".to_string())), Some(vec![0, 1, 3]));
+        assert_eq!(ids(Filter::min_stars(101)), Some(vec![0, 2, 3]));
+        assert_eq!(ids(Filter::Stars { min: Some(100), max: Some(8000) }), Some(vec![1, 3]));
+        assert_eq!(ids(Filter::And(vec![
+            Filter::Language("Rust".to_string()),
+            Filter::min_stars(101),
+            Filter::Or(vec![Filter::License("MIT".to_string()), Filter::License("Apache-2.0".to_string())])
+        ])), Some(vec![0, 3]));
+        assert_eq!(ids(Filter::Not(Box::new(Filter::Language("Go".to_string())))), None);
+        // the indexable half still narrows a conjunction down
+        assert_eq!(ids(Filter::And(vec![
+            Filter::Language("Go".to_string()),
+            Filter::Not(Box::new(Filter::min_stars(10)))
+        ])), Some(vec![2]));
+
+        db.persist_document(1, &repo("tokio-rs/tokio", "C", "MIT", 100)).unwrap();
+        db.remove_entry(0, 0).unwrap();
+        assert_eq!(ids(Filter::Language("Rust".to_string())), Some(vec![3]));
+        assert_eq!(ids(Filter::Language("C".to_string())), Some(vec![1]));
+        assert_eq!(ids(Filter::License("MIT".to_string())), Some(vec![1]));
+
+        // a mangled index key is an error, not a panic
+        for mangled in [vec![0, 0, 0, 1], vec![0, 0, 0, 1, 0]] {
+            let key = [STARS_INDEX_PREFIX, &mangled].concat();
+            db.database.put(&key, b"").unwrap();
+            assert!(matches!(db.ids_matching(&Filter::min_stars(0)), Err(KathleenError::Corrupt { .. })), "{mangled:?}");
+            db.database.delete(&key).unwrap();
+        }
+        assert_eq!(ids(Filter::min_stars(0)), Some(vec![1, 2, 3]));
+
+        // the indexes fold case the way Filter::matches does, beyond ascii too
+        let accented = repo("oeko/oeko", "Ökolang", "MIT", 5);
+        db.persist_repo(0, 4, &entry, &Embedding::zeros(&params), &accented.name, Some(&accented)).unwrap();
+        for wanted in ["ökolang", "ÖKOLANG"] {
+            let filter = Filter::Language(wanted.to_string());
+            assert!(filter.matches(&accented));
+            assert_eq!(ids(filter), Some(vec![4]));
+        }
+    }
+
+    #[test]
+    fn documents_get_indexed_on_upgrade() {
+        let (db, path, params) = fresh_database("./dbre_filters_upgrade");
+        // documents as written before the indexes existed
+        db.database.put(document_key(4), encode(&repo("rust-lang/rust", "Rust", "MIT", 90000)).unwrap()).unwrap();
+        db.persist_schema(3).unwrap();
+        drop(db);
+
+        let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+        assert_eq!(db.ids_matching(&Filter::Language("Rust".to_string())).unwrap(), Some(HashSet::from([4])));
+    }
+
+    #[test]
+    fn cluster_graph_survives_reopening() {
+        let (db, path, params) = fresh_database("./dbre_graph");
+        // keys right after the edges must not be read as edges
+        db.database.put(b"edgf", b"").unwrap();
+        let edge = |queries| Edge { queries, strength: queries as f64, centroid_distance: 2.5 };
+        db.persist_edges([((0, 3), edge(1)), ((2, 7), edge(4))]).unwrap();
+        db.persist_edges([((0, 3), edge(2))]).unwrap();
+        drop(db);
+
+        let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+        let graph = db.load_graph().unwrap();
+        assert_eq!(graph, ClusterGraph::from_edges([((0, 3), edge(2)), ((7, 2), edge(4))]));
+        db.clear_graph().unwrap();
+        assert!(db.load_graph().unwrap().is_empty());
+        assert!(db.database.get(b"edgf").unwrap().is_some());
+    }
+
+    #[test]
+    fn edges_get_weighed_on_upgrade() {
+        let (db, path, params) = fresh_database("./dbre_graph_upgrade");
+        // edges as written before they had a strength
+        #[derive(Serialize)]
+        struct CountedEdge {
+            queries: u32,
+            centroid_distance: f64
+        }
+        db.database.put(edge_db_key((1, 4)), encode(&CountedEdge { queries: 3, centroid_distance: 8. }).unwrap()).unwrap();
+        db.persist_schema(4).unwrap();
+        drop(db);
+
+        let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+        let graph = db.load_graph().unwrap();
+        assert_eq!(graph.edge(1, 4), Some(&Edge { queries: 3, strength: 3., centroid_distance: 8. }));
+        assert_eq!(graph.edge(1, 4).unwrap().weight(), 2.);
+    }
+
+    #[test]
+    fn profiles_are_stored_by_login() {
+        let (db, _, _) = fresh_database("./dbre_profiles");
+        let profile = UserProfile { login: "octocat".to_string(), home: 3, repos: 8 };
+        db.persist_profile(&profile).unwrap();
+        assert_eq!(db.load_profile("octocat").unwrap(), Some(profile));
+        assert_eq!(db.load_profile("octo").unwrap(), None);
+        db.clear_profiles().unwrap();
+        assert_eq!(db.load_profile("octocat").unwrap(), None);
+    }
+
+    #[test]
+    fn sdc_tables_get_built_on_upgrade() {
+        let params = IndexParams::new(4, 2, 2, 2).unwrap();
+        let path = fresh_db("./dbre_sdc_upgrade");
+        let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+        assert_eq!(db.load_sdc_tables().unwrap(), None);
+        let codebook = vec![Embedding::zeros(&params), Embedding::new(vec![Segment::new(vec![1., 2.]), Segment::new(vec![3., 4.])])];
+        let pq_codebook = PqCodebook::new(vec![
+            vec![Segment::new(vec![0.5, 0.]), Segment::new(vec![0., 0.5])],
+            vec![Segment::new(vec![-1., 0.]), Segment::new(vec![0., -1.])]
+        ]);
+        // trained before the tables existed
+        db.persist_codebook(codebook.clone()).unwrap();
+        db.persist_pq_codebook(pq_codebook.clone()).unwrap();
+        db.persist_schema(5).unwrap();
+        drop(db);
+
+        let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+        let tables = SdcTables::build(&codebook, &pq_codebook);
+        assert_eq!(db.load_sdc_tables().unwrap(), Some(tables.clone()));
+
+        // one record per list and per subspace, each checked on its own
+        db.database.put(sdc_subspace_key(1), encode(&tables.subspace(0)[..1].to_vec()).unwrap()).unwrap();
+        assert!(matches!(db.load_sdc_tables(), Err(KathleenError::Corrupt { .. })));
+        db.database.delete(sdc_subspace_key(1)).unwrap();
+        assert!(matches!(db.load_sdc_tables(), Err(KathleenError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn schema_is_checked_on_open() {
+        let (db, path, params) = fresh_database("./dbre_schema");
+        assert_eq!(db.load_schema().unwrap().map(|schema| schema.version), Some(SCHEMA_VERSION));
+        drop(db);
+
+        let other = IndexParams::new(12, 3, 8, 8).unwrap();
+        assert!(matches!(DatabaseWrapper::open(path, &other), Err(KathleenError::InvalidParams(_))));
+
+        let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+        db.persist_schema(SCHEMA_VERSION + 1).unwrap();
+        drop(db);
+        assert!(matches!(DatabaseWrapper::open(path, &params), Err(KathleenError::UnsupportedSchema { .. })));
+    }
+
+    /// InvertedIndex(Vec<AvlWrapper>) as the first version stored it, every list in its string form
+    #[derive(Serialize)]
+    struct LegacyIvf(Vec<String>);
+
+    #[test]
+    fn legacy_databases_get_migrated() {
+        let (path, params) = (fresh_db("./dbre_blob"), test_params());
+        let mut lists = vec!["{}".to_string(); params.nlist()];
+        let db = DB::open(&db_options(), path).unwrap();
+        // written with three subspaces
+        lists[2] = "{7: [1, 1, 1];2\n}".to_string();
+        db.put(b"ivf", encode(&LegacyIvf(lists.clone())).unwrap()).unwrap();
+        drop(db);
+        assert!(matches!(DatabaseWrapper::open(path, &params), Err(KathleenError::InvalidParams(_))));
+
+        let db = DB::open(&db_options(), path).unwrap();
+        lists[2] = "{7: [1, 1, 1, 1];2\n9: [0, 3, 3, 1];2\n}".to_string();
+        db.put(b"ivf", encode(&LegacyIvf(lists)).unwrap()).unwrap();
+        drop(db);
+
+        // params are checked before anything gets touched
+        let other = IndexParams::new(12, 4, 8, 4).unwrap();
+        assert!(matches!(DatabaseWrapper::open(path, &other), Err(KathleenError::InvalidParams(_))));
+
+        let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+        // the codes are gone, their ids wait for new embeddings
+        let ivf = InvertedIndex::empty(params);
+        assert_eq!(ivf, db.load_ivf().unwrap());
+        assert_eq!(db.load_stale().unwrap(), HashSet::from([7, 9]));
+        assert_eq!(db.database.get(b"ivf").unwrap(), None);
+        assert_eq!(db.load_schema().unwrap().map(|schema| schema.version), Some(SCHEMA_VERSION));
+
+        // column families without a schema record, params under their own key
+        db.database.delete(b"schema").unwrap();
+        db.database.put(b"ivf_params", encode(&params).unwrap()).unwrap();
+        drop(db);
+        let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+        assert_eq!(ivf, db.load_ivf().unwrap());
+        assert_eq!(db.load_stale().unwrap(), HashSet::from([7, 9]));
+        assert_eq!(db.database.get(b"ivf_params").unwrap(), None);
+        assert_eq!(db.load_schema().unwrap().map(|schema| schema.version), Some(SCHEMA_VERSION));
     }
 }
@@ -0,0 +1,39 @@
+use thiserror::Error;
+
+/// everything that can go wrong inside kathleen, so that callers (i.e. the service)
+/// get a value to act upon instead of a panic
+#[derive(Debug, Error)]
+pub enum KathleenError {
+    /// rocksdb failed to read or write
+    #[error("storage error: {0}")]
+    Storage(#[from] rocksdb::Error),
+    /// a stored record could not be decoded
+    #[error("corrupt record under {key:?}: {reason}")]
+    Corrupt { key: String, reason: String },
+    /// a value could not be encoded before being stored
+    #[error("serialization failed: {0}")]
+    Serialization(String),
+    /// malformed textual input (embeddings, inverted list entries...)
+    #[error("parse error: {0}")]
+    Parse(String),
+    #[error("expected {expected} dimensions, got {got}")]
+    DimensionMismatch { expected: usize, got: usize },
+    #[error("model not trained")]
+    NotTrained,
+    #[error("k-means training failed: {0}")]
+    Training(String),
+    /// the database was written by a newer version of kathleen
+    #[error("schema version {found} is newer than the supported {supported}")]
+    UnsupportedSchema { found: u32, supported: u32 },
+    #[error("invalid parameters: {0}")]
+    InvalidParams(String),
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("{0} already exists")]
+    AlreadyExists(String),
+    /// every vector id has been handed out
+    #[error("no vector ids left")]
+    IdsExhausted,
+}
+
+pub type KathleenResult<T> = Result<T, KathleenError>;
@@ -0,0 +1,101 @@
+use serde::{Serialize, Deserialize};
+use super::primitive_types::RepoMetadata;
+
+// FILTERS
+// restrict a search to repositories whose metadata matches an expression, e.g.
+// "only Rust, more than 100 stars, MIT or Apache":
+//     Filter::And(vec![
+//         Filter::Language("Rust".to_string()),
+//         Filter::min_stars(101),
+//         Filter::Or(vec![Filter::License("MIT".to_string()), Filter::License("Apache-2.0".to_string())])
+//     ])
+// text comparisons ignore case, unicode included. vectors without a metadata document never match
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Filter {
+    Language(String),
+    /// SPDX identifier, e.g. "MIT"
+    License(String),
+    /// repo has this topic among others
+    Topic(String),
+    /// inclusive bounds, a missing one is unbounded
+    Stars { min: Option<u32>, max: Option<u32> },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>)
+}
+
+impl Filter {
+    pub fn min_stars(min: u32) -> Self {
+        Filter::Stars { min: Some(min), max: None }
+    }
+
+    pub fn matches(&self, metadata: &RepoMetadata) -> bool {
+        let same = |value: &str, wanted: &str| fold_case(value) == fold_case(wanted);
+        match self {
+            Filter::Language(language) => metadata.language.as_ref().is_some_and(|value| same(value, language)),
+            Filter::License(license) => metadata.license.as_ref().is_some_and(|value| same(value, license)),
+            Filter::Topic(topic) => metadata.topics.iter().any(|value| same(value, topic)),
+            Filter::Stars { min, max } => !min.is_some_and(|min| metadata.stars < min) && !max.is_some_and(|max| metadata.stars > max),
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(metadata)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(metadata)),
+            Filter::Not(filter) => !filter.matches(metadata)
+        }
+    }
+
+    /// whether the secondary indexes alone tell which vectors match,
+    /// negations need every document to be looked at
+    pub fn is_indexed(&self) -> bool {
+        match self {
+            Filter::And(filters) | Filter::Or(filters) => filters.iter().all(Filter::is_indexed),
+            Filter::Not(_) => false,
+            _ => true
+        }
+    }
+}
+
+/// what text values are compared as, here and in the secondary indexes, so both always agree
+pub fn fold_case(value: &str) -> String {
+    value.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rust_repo() -> RepoMetadata {
+        RepoMetadata {
+            name: "rust-lang/rust".to_string(),
+            description: None,
+            language: Some("Rust".to_string()),
+            license: Some("Apache-2.0".to_string()),
+            stars: 90000,
+            topics: vec!["compiler".to_string(), "language".to_string()]
+        }
+    }
+
+    #[test]
+    fn filters_match_metadata() {
+        let repo = rust_repo();
+        let wanted = Filter::And(vec![
+            Filter::Language("rust".to_string()),
+            Filter::min_stars(101),
+            Filter::Or(vec![Filter::License("MIT".to_string()), Filter::License("apache-2.0".to_string())])
+        ]);
+        assert!(wanted.matches(&repo));
+        assert!(Filter::Topic("Compiler".to_string()).matches(&repo));
+        assert!(!Filter::Stars { min: None, max: Some(100) }.matches(&repo));
+        assert!(!Filter::Not(Box::new(wanted.clone())).matches(&repo));
+        assert!(!Filter::License("MIT".to_string()).matches(&RepoMetadata { license: None, ..repo }));
+        // empty conjunctions let everything through, empty disjunctions nothing
+        assert!(Filter::And(vec![]).matches(&rust_repo()));
+        assert!(!Filter::Or(vec![]).matches(&rust_repo()));
+        // not only ascii
+        let accented = RepoMetadata { language: Some("Ökolang".to_string()), ..rust_repo() };
+        assert!(Filter::Language("ÖKOLANG".to_string()).matches(&accented));
+        assert!(Filter::Language("ökolang".to_string()).matches(&accented));
+
+        assert!(wanted.is_indexed());
+        assert!(!Filter::And(vec![wanted.clone(), Filter::Not(Box::new(wanted))]).is_indexed());
+    }
+}
@@ -0,0 +1,1116 @@
+use std::{collections::{HashMap, HashSet}, path::Path, sync::Mutex};
+
+use super::{
+    db_api::{DatabaseWrapper, Open},
+    ivfpq::{InvertedIndex, IndexParams, Model, SearchParams, decode, refine, search, search_by_code, search_entries, search_filtered},
+    filter::Filter,
+    aggregation::{Aggregation, centroid, combine, entries_of},
+    cluster_graph::{ClusterGraph, Station, StationCriterion},
+    quantization::QuantizationReport,
+    sdc::SdcTables,
+    primitive_types::{Clusters, Codebook, DBResult, Embedding, IVListEntry, PqCode, PqCodebook, RepoMetadata, SearchHit, UserProfile},
+    error::{KathleenError, KathleenResult}
+};
+
+// INDEX
+// ties together the in-memory IVF, both quantizers and the database backing them,
+// so that callers don't have to thread every piece through each call
+
+pub struct IvfPqIndex {
+    db: DatabaseWrapper<Open>,
+    ividx: InvertedIndex,
+    codebook: Codebook,
+    pq_codebook: PqCodebook,
+    model: Model,
+    // code to code distances, None until trained
+    sdc: Option<SdcTables>,
+    // id -> cluster lookup, so entries can be found without scanning every list
+    locations: HashMap<u32, Clusters>,
+    // ids whose codes were dropped, out of every list until they get upserted
+    stale: HashSet<u32>,
+    // grows with every search, which only borrows the index
+    graph: Mutex<ClusterGraph>
+}
+
+impl IvfPqIndex {
+    /// loads whatever was persisted under path, which has to have been created with the same params
+    pub fn open(path: &Path, params: IndexParams) -> KathleenResult<Self> {
+        let db = DatabaseWrapper::open(path, &params)?;
+        let ividx = db.load_ivf()?;
+        let codebook = db.load_codebook(ividx.params())?;
+        let pq_codebook = db.load_pq_codebook(ividx.params())?;
+        let stale = db.load_stale()?;
+        // entries written before ids came from the database must never be handed out again
+        if let Some(max_id) = ividx.max_id().into_iter().chain(stale.iter().copied()).max() {
+            db.seed_ids(max_id.saturating_add(1))?;
+        }
+        let model = db.load_model()?;
+        let sdc = db.load_sdc_tables()?;
+        let locations = ividx.locations();
+        let graph = Mutex::new(db.load_graph()?);
+        Ok(Self {
+            db,
+            ividx,
+            codebook,
+            pq_codebook,
+            model,
+            sdc,
+            locations,
+            stale,
+            graph
+        })
+    }
+
+    pub fn params(&self) -> &IndexParams {
+        self.ividx.params()
+    }
+
+    /// trains both quantizers and stores the training embeddings, returns the ids they got.
+    /// repos stored before get re-encoded from their raw vectors under the new codebooks,
+    /// the ones without a raw vector can't be and turn stale, see stale_ids.
+    /// the cluster graph and the user profiles start over, their clusters are gone
+    pub fn train(&mut self, embs: &[Embedding]) -> KathleenResult<Vec<u32>> {
+        let mut stored = Vec::new();
+        let mut code_only = Vec::new();
+        for (vec_id, raw) in self.stored_vectors()? {
+            match raw {
+                Some(raw) => stored.push((vec_id, raw)),
+                None => code_only.push(vec_id)
+            }
+        }
+        let ids = embs.iter()
+            .map(|_| self.db.next_id())
+            .collect::<DBResult<Vec<u32>>>()?;
+        let (codebook, pq_codebook) = self.model.k_means(&mut self.ividx, embs, &ids)?;
+        self.codebook = codebook;
+        self.pq_codebook = pq_codebook;
+        let sdc = SdcTables::build(&self.codebook, &self.pq_codebook);
+        self.locations = self.ividx.locations();
+        for (vec_id, raw) in &stored {
+            self.place(*vec_id, raw)?;
+        }
+        self.stale.extend(code_only);
+        self.graph.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+        let vectors = ids.iter().zip(embs).chain(stored.iter().map(|(id, raw)| (id, raw)))
+            .map(|(id, emb)| (self.locations[id], *id, emb));
+        self.db.persist_training(&self.ividx, &self.codebook, &self.pq_codebook, &sdc, vectors, &self.stale)?;
+        self.sdc = Some(sdc);
+        Ok(ids)
+    }
+
+    /// every stored repo with its raw vector by id, None for the ones that only kept their code
+    fn stored_vectors(&self) -> KathleenResult<Vec<(u32, Option<Embedding>)>> {
+        let mut ids = self.locations.keys().copied().collect::<Vec<u32>>();
+        ids.sort();
+        ids.into_iter()
+            .map(|vec_id| Ok((vec_id, self.db.load_vector(self.locations[&vec_id], vec_id)?)))
+            .collect()
+    }
+
+    /// stores the embedding in the list of its nearest centroid and returns the id assigned to it
+    pub fn add(&mut self, emb: &Embedding) -> KathleenResult<u32> {
+        let vec_id = self.db.next_id()?;
+        let cluster = self.place(vec_id, emb)?;
+        self.db.persist_entry(cluster, vec_id, self.entry(cluster, vec_id), emb)?;
+        Ok(vec_id)
+    }
+
+    /// drops the vector from its inverted list together with its external key,
+    /// returns whether there was anything to remove
+    pub fn remove(&mut self, vec_id: u32) -> KathleenResult<bool> {
+        // memory only follows once the database did
+        match self.locations.get(&vec_id).copied() {
+            Some(cluster) => {
+                self.db.remove_entry(cluster, vec_id)?;
+                self.locations.remove(&vec_id);
+                self.ividx.remove_from_cluster(cluster, vec_id);
+            },
+            None if self.stale.contains(&vec_id) => {
+                self.db.remove_stale(vec_id)?;
+                self.stale.remove(&vec_id);
+            },
+            None => return Ok(false)
+        }
+        if let Some(key) = self.key_of(vec_id)? {
+            self.db.unmap_key(&key)?;
+        }
+        Ok(true)
+    }
+
+    /// replaces the embedding stored under vec_id, moving it to whatever list it now maps to.
+    /// stale vectors get encoded again and are searchable from then on
+    pub fn upsert(&mut self, vec_id: u32, emb: &Embedding) -> KathleenResult<()> {
+        let Some(cluster) = self.locations.get(&vec_id).copied() else {
+            return match self.stale.contains(&vec_id) {
+                true => self.restore(vec_id, emb),
+                false => Err(KathleenError::NotFound(format!("vector {vec_id}")))
+            };
+        };
+        // a rejected embedding must leave the stored one where it is
+        let new_cluster = self.model.predict(emb)?;
+        let previous = self.ividx.remove_from_cluster(cluster, vec_id);
+        let moved = self.place_in(new_cluster, vec_id, emb)
+            .and_then(|_| self.db.move_entry(cluster, new_cluster, vec_id, self.entry(new_cluster, vec_id), emb));
+        if moved.is_err() {
+            // back to what the database still holds
+            self.ividx.remove_from_cluster(new_cluster, vec_id);
+            if let Some(previous) = previous {
+                self.ividx.get_cluster_mut(cluster).insert(vec_id, previous);
+            }
+            self.locations.insert(vec_id, cluster);
+        }
+        moved
+    }
+
+    fn restore(&mut self, vec_id: u32, emb: &Embedding) -> KathleenResult<()> {
+        let cluster = self.place(vec_id, emb)?;
+        if let Err(e) = self.db.restore_entry(cluster, vec_id, self.entry(cluster, vec_id), emb) {
+            self.ividx.remove_from_cluster(cluster, vec_id);
+            self.locations.remove(&vec_id);
+            return Err(e);
+        }
+        self.stale.remove(&vec_id);
+        Ok(())
+    }
+
+    /// vectors whose codes couldn't be kept, the ones of indexes written before the PQ codebook was stored
+    /// and the ones a retraining had no raw vector to re-encode from.
+    /// they stay out of every search, with their keys and metadata, until upserted with a new embedding
+    pub fn stale_ids(&self) -> Vec<u32> {
+        let mut ids = self.stale.iter().copied().collect::<Vec<u32>>();
+        ids.sort();
+        ids
+    }
+
+    /// key based remove, returns the id the key pointed to
+    pub fn remove_key(&mut self, key: &str) -> KathleenResult<Option<u32>> {
+        match self.id_of(key)? {
+            Some(vec_id) => {
+                self.remove(vec_id)?;
+                // the key may outlive a vector removed by an older version
+                self.db.unmap_key(key)?;
+                Ok(Some(vec_id))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// inserts the repo if the key is new, replaces its embedding otherwise
+    pub fn upsert_key(&mut self, key: &str, emb: &Embedding) -> KathleenResult<u32> {
+        match self.id_of(key)? {
+            Some(vec_id) => {
+                self.upsert(vec_id, emb)?;
+                Ok(vec_id)
+            },
+            None => self.insert(key, emb)
+        }
+    }
+
+    /// encodes emb into the list of its nearest centroid under vec_id, returns that list
+    /// only the in-memory index is touched, writing the entry is up to the caller
+    fn place(&mut self, vec_id: u32, emb: &Embedding) -> KathleenResult<Clusters> {
+        let cluster = self.model.predict(emb)?;
+        self.place_in(cluster, vec_id, emb)?;
+        Ok(cluster)
+    }
+
+    fn place_in(&mut self, cluster: Clusters, vec_id: u32, emb: &Embedding) -> KathleenResult<()> {
+        self.ividx.add_embedding_to_cluster(cluster, vec_id, emb, &self.codebook, &self.pq_codebook)?;
+        self.locations.insert(vec_id, cluster);
+        Ok(())
+    }
+
+    fn entry(&self, cluster: Clusters, vec_id: u32) -> &IVListEntry {
+        &self.ividx.get_cluster(cluster)[&vec_id]
+    }
+
+    /// same as add, but the vector is also reachable through an external key like "owner/repo"
+    pub fn insert(&mut self, key: &str, emb: &Embedding) -> KathleenResult<u32> {
+        self.insert_repo(key, emb, None)
+    }
+
+    /// same as insert, the metadata document comes back with every hit on the repo
+    pub fn insert_with_metadata(&mut self, key: &str, emb: &Embedding, metadata: &RepoMetadata) -> KathleenResult<u32> {
+        self.insert_repo(key, emb, Some(metadata))
+    }
+
+    fn insert_repo(&mut self, key: &str, emb: &Embedding, metadata: Option<&RepoMetadata>) -> KathleenResult<u32> {
+        if self.db.id_for_key(key)?.is_some() {
+            return Err(KathleenError::AlreadyExists(format!("key {key}")));
+        }
+        let vec_id = self.db.next_id()?;
+        let cluster = self.place(vec_id, emb)?;
+        self.db.persist_repo(cluster, vec_id, self.entry(cluster, vec_id), emb, key, metadata)?;
+        Ok(vec_id)
+    }
+
+    /// replaces the metadata document of a stored vector
+    pub fn set_metadata(&mut self, vec_id: u32, metadata: &RepoMetadata) -> KathleenResult<()> {
+        if !self.locations.contains_key(&vec_id) && !self.stale.contains(&vec_id) {
+            return Err(KathleenError::NotFound(format!("vector {vec_id}")));
+        }
+        self.db.persist_document(vec_id, metadata)
+    }
+
+    pub fn metadata_of(&self, vec_id: u32) -> KathleenResult<Option<RepoMetadata>> {
+        self.db.load_document(vec_id)
+    }
+
+    pub fn id_of(&self, key: &str) -> KathleenResult<Option<u32>> {
+        self.db.id_for_key(key)
+    }
+
+    pub fn key_of(&self, vec_id: u32) -> KathleenResult<Option<String>> {
+        self.db.key_for_id(vec_id)
+    }
+
+    /// hits come back with the external key and metadata of every vector that has them,
+    /// filtered by their metadata and re-ranked against the raw vectors in the cluster environments
+    /// if params ask for it. clusters the hits came from get linked to the cluster of their query
+    pub fn search(&self, query_vectors: &[Embedding], k: usize, params: &SearchParams) -> KathleenResult<Vec<Vec<SearchHit>>> {
+        let origins = match self.model.is_trained() {
+            true => query_vectors.iter().map(|qv| self.model.predict(qv)).collect::<KathleenResult<Vec<Clusters>>>()?,
+            // nothing to find anyway
+            false => Vec::new()
+        };
+        self.search_from(query_vectors, &origins, k, params)
+    }
+
+    /// same as search, but the clusters the hits came from get linked to the home cluster of the user
+    pub fn search_as(&self, login: &str, query_vectors: &[Embedding], k: usize, params: &SearchParams) -> KathleenResult<Vec<Vec<SearchHit>>> {
+        let profile = self.user(login)?.ok_or_else(|| KathleenError::NotFound(format!("user {login}")))?;
+        self.search_from(query_vectors, &vec![profile.home; query_vectors.len()], k, params)
+    }
+
+    /// origins holds the cluster each query comes from
+    fn search_from(&self, query_vectors: &[Embedding], origins: &[Clusters], k: usize, params: &SearchParams) -> KathleenResult<Vec<Vec<SearchHit>>> {
+        let results = self.search_unrecorded(query_vectors, k, params)?;
+        self.record_crossings(origins, &results)?;
+        Ok(results)
+    }
+
+    /// search that leaves the cluster graph alone, for the queries the index fans out on its own.
+    /// only what a caller asked for directly counts as querying clusters together
+    fn search_unrecorded(&self, query_vectors: &[Embedding], k: usize, params: &SearchParams) -> KathleenResult<Vec<Vec<SearchHit>>> {
+        let mut results = match &params.filter {
+            Some(filter) => self.search_matching(query_vectors, params.candidates(k), params, filter)?,
+            None => search(&self.ividx, query_vectors, &self.codebook, &self.pq_codebook, params.candidates(k), params)?
+        };
+        if params.refine_factor.is_some() {
+            results = query_vectors.iter()
+                .zip(results)
+                .map(|(qv, candidates)| refine(qv, candidates, k, self.params().metric(), |hit| self.db.load_vector(hit.cluster, hit.id)))
+                .collect::<KathleenResult<Vec<Vec<SearchHit>>>>()?;
+        }
+        for hit in results.iter_mut().flatten() {
+            self.describe(hit)?;
+        }
+        Ok(results)
+    }
+
+    /// adds an edge, or reinforces it, between the cluster each query comes from
+    /// and every other cluster its hits came from. the similarity of a crossing shrinks with
+    /// how much farther the best hit in that cluster is than the best hit overall
+    fn record_crossings(&self, origins: &[Clusters], results: &[Vec<SearchHit>]) -> KathleenResult<()> {
+        let mut graph = self.graph.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut changed = HashMap::new();
+        for (origin, hits) in origins.iter().zip(results) {
+            let mut nearest: HashMap<Clusters, f64> = HashMap::new();
+            for hit in hits {
+                let dist = nearest.entry(hit.cluster).or_insert(hit.distance);
+                *dist = dist.min(hit.distance);
+            }
+            let best = nearest.values().copied().fold(f64::INFINITY, f64::min);
+            for (target, dist) in nearest {
+                let similarity = 1. / (1. + dist - best);
+                if let Some((clusters, edge)) = graph.record(*origin, target, similarity, &self.codebook, self.params().metric()) {
+                    changed.insert(clusters, edge);
+                }
+            }
+        }
+        self.db.persist_edges(changed)
+    }
+
+    /// derives the home cluster of a GitHub user from the embeddings of their own repos,
+    /// the cluster most of them fall in (the lowest one on ties), and stores it under their login
+    pub fn set_user(&mut self, login: &str, repos: &[Embedding]) -> KathleenResult<UserProfile> {
+        if repos.is_empty() {
+            return Err(KathleenError::InvalidParams(format!("user {login} needs at least one repo")));
+        }
+        let mut counts: HashMap<Clusters, u32> = HashMap::new();
+        for repo in repos {
+            *counts.entry(self.model.predict(repo)?).or_default() += 1;
+        }
+        let home = counts.into_iter()
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|(cluster, _)| cluster)
+            .unwrap_or_default();
+        let profile = UserProfile { login: login.to_string(), home, repos: repos.len() as u32 };
+        self.db.persist_profile(&profile)?;
+        Ok(profile)
+    }
+
+    pub fn user(&self, login: &str) -> KathleenResult<Option<UserProfile>> {
+        self.db.load_profile(login)
+    }
+
+    /// snapshot of the cluster graph built so far
+    pub fn cluster_graph(&self) -> ClusterGraph {
+        self.graph.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    /// fills in what's stored about the hit besides its vector
+    fn describe(&self, hit: &mut SearchHit) -> KathleenResult<()> {
+        hit.key = self.key_of(hit.id)?;
+        hit.metadata = self.metadata_of(hit.id)?;
+        Ok(())
+    }
+
+    /// a single list with the k repos most similar to the whole set of query vectors,
+    /// none of the ids in exclude (usually the query repos themselves) shows up in it
+    pub fn search_set(&self, query_vectors: &[Embedding], exclude: &[u32], k: usize, aggregation: Aggregation, params: &SearchParams) -> KathleenResult<Vec<SearchHit>> {
+        if query_vectors.is_empty() {
+            return Err(KathleenError::InvalidParams("a set query needs at least one query vector".to_string()));
+        }
+        // room for the excluded repos, which are likely among the nearest
+        let depth = k.saturating_add(exclude.len());
+        let per_query = match aggregation {
+            Aggregation::Centroid => self.search_unrecorded(&[centroid(query_vectors, self.params())?], depth, params)?,
+            Aggregation::ReciprocalRank => self.search_unrecorded(query_vectors, depth, params)?,
+            Aggregation::MeanDistance | Aggregation::MaxDistance => {
+                // every candidate needs its distance to every query, not only to the ones that found it
+                let entries = entries_of(&self.search_unrecorded(query_vectors, depth, params)?);
+                let distances = search_entries(&self.ividx, query_vectors, &self.codebook, &self.pq_codebook, entries.len().max(1), &entries)?;
+                match params.refine_factor {
+                    Some(_) => query_vectors.iter()
+                        .zip(distances)
+                        .map(|(qv, hits)| {
+                            let n = hits.len().max(1);
+                            refine(qv, hits, n, self.params().metric(), |hit| self.db.load_vector(hit.cluster, hit.id))
+                        })
+                        .collect::<KathleenResult<Vec<Vec<SearchHit>>>>()?,
+                    None => distances
+                }
+            }
+        };
+        let mut hits = combine(per_query, aggregation).into_iter()
+            .filter(|hit| !exclude.contains(&hit.id))
+            .take(k)
+            .collect::<Vec<SearchHit>>();
+        for hit in hits.iter_mut() {
+            self.describe(hit)?;
+        }
+        Ok(hits)
+    }
+
+    /// set query over stored repos, their raw vectors are the query vectors
+    /// and they never show up in the results
+    pub fn search_similar_to(&self, keys: &[&str], k: usize, aggregation: Aggregation, params: &SearchParams) -> KathleenResult<Vec<SearchHit>> {
+        let (ids, query_vectors) = self.stored_queries(keys)?;
+        self.search_set(&query_vectors, &ids, k, aggregation, params)
+    }
+
+    /// ids and raw vectors of stored repos
+    fn stored_queries(&self, keys: &[&str]) -> KathleenResult<(Vec<u32>, Vec<Embedding>)> {
+        let mut ids = Vec::with_capacity(keys.len());
+        let mut query_vectors = Vec::with_capacity(keys.len());
+        for key in keys {
+            let vec_id = self.id_of(key)?.ok_or_else(|| KathleenError::NotFound(format!("key {key}")))?;
+            let raw = match self.locations.get(&vec_id) {
+                Some(cluster) => self.db.load_vector(*cluster, vec_id)?,
+                None => None
+            };
+            query_vectors.push(raw.ok_or_else(|| KathleenError::NotFound(format!("raw vector of {key}")))?);
+            ids.push(vec_id);
+        }
+        Ok((ids, query_vectors))
+    }
+
+    /// k repos most similar to the stored one under vec_id, which never shows up itself.
+    /// its raw vector is the query when the environment still has it, otherwise its code gets
+    /// compared with the other codes through the SDC tables, which can't be re-ranked
+    pub fn search_by_id(&self, vec_id: u32, k: usize, params: &SearchParams) -> KathleenResult<Vec<SearchHit>> {
+        let cluster = *self.locations.get(&vec_id).ok_or_else(|| KathleenError::NotFound(format!("vector {vec_id}")))?;
+        if let Some(raw) = self.db.load_vector(cluster, vec_id)? {
+            let mut hits = self.search_unrecorded(&[raw], k.saturating_add(1), params)?.remove(0);
+            hits.retain(|hit| hit.id != vec_id);
+            hits.truncate(k);
+            return Ok(hits);
+        }
+        let sdc = self.sdc.as_ref().ok_or(KathleenError::NotTrained)?;
+        let mut hits = search_by_code(&self.ividx, (vec_id, cluster), sdc, k, params, |id| {
+            match &params.filter {
+                Some(filter) => Ok(self.metadata_of(id)?.is_some_and(|metadata| filter.matches(&metadata))),
+                None => Ok(true)
+            }
+        })?;
+        for hit in hits.iter_mut() {
+            self.describe(hit)?;
+        }
+        Ok(hits)
+    }
+
+    /// finds the station of the clusters the given repos live in through the cluster graph,
+    /// then returns the k repos of that cluster nearest to the mean of the query repos, leaving them out.
+    /// None if the graph doesn't link every one of their clusters yet
+    pub fn search_station(&self, keys: &[&str], k: usize, criterion: StationCriterion, params: &SearchParams) -> KathleenResult<Option<Station>> {
+        let (ids, query_vectors) = self.stored_queries(keys)?;
+        let clusters = ids.iter().map(|id| self.locations[id]).collect::<Vec<Clusters>>();
+        let Some((cluster, distance)) = self.cluster_graph().station(&clusters, criterion) else {
+            return Ok(None);
+        };
+        let query = centroid(&query_vectors, self.params())?;
+        let mut entries = Vec::new();
+        for (vec_id, _) in self.ividx.get_cluster(cluster).iter() {
+            if ids.contains(vec_id) {
+                continue;
+            }
+            if let Some(filter) = &params.filter {
+                if !self.metadata_of(*vec_id)?.is_some_and(|metadata| filter.matches(&metadata)) {
+                    continue;
+                }
+            }
+            entries.push((*vec_id, cluster));
+        }
+        let mut hits = search_entries(&self.ividx, std::slice::from_ref(&query), &self.codebook, &self.pq_codebook, params.candidates(k), &entries)?
+            .remove(0);
+        if params.refine_factor.is_some() {
+            hits = refine(&query, hits, k, self.params().metric(), |hit| self.db.load_vector(hit.cluster, hit.id))?;
+        }
+        for hit in hits.iter_mut() {
+            self.describe(hit)?;
+        }
+        Ok(Some(Station { cluster, distance, hits }))
+    }
+
+    /// filters get resolved through the secondary indexes when possible. if they leave fewer entries
+    /// than the probed lists hold, only those get ranked. otherwise the lists are scanned as usual,
+    /// skipping whatever doesn't match
+    fn search_matching(&self, query_vectors: &[Embedding], n: usize, params: &SearchParams, filter: &Filter) -> KathleenResult<Vec<Vec<SearchHit>>> {
+        let indexed = self.db.ids_matching(filter)?;
+        let exact = filter.is_indexed();
+        if let (Some(ids), true) = (&indexed, exact) {
+            let probed = self.locations.len() * params.nprobe / self.params().nlist();
+            if ids.len() <= probed {
+                let entries = ids.iter()
+                    .filter_map(|id| self.locations.get(id).map(|cluster| (*id, *cluster)))
+                    .collect::<Vec<(u32, Clusters)>>();
+                return search_entries(&self.ividx, query_vectors, &self.codebook, &self.pq_codebook, n, &entries);
+            }
+        }
+        let unfiltered = SearchParams { filter: None, ..params.clone() };
+        search_filtered(&self.ividx, query_vectors, &self.codebook, &self.pq_codebook, n, &unfiltered, |vec_id| {
+            if indexed.as_ref().is_some_and(|ids| !ids.contains(&vec_id)) {
+                return Ok(false);
+            }
+            if exact {
+                return Ok(true);
+            }
+            Ok(self.metadata_of(vec_id)?.is_some_and(|metadata| filter.matches(&metadata)))
+        })
+    }
+
+    /// approximate vector a code stands for in the list of cluster
+    pub fn decode(&self, code: &PqCode, cluster: Clusters) -> KathleenResult<Embedding> {
+        decode(code, cluster, &self.codebook, &self.pq_codebook)
+    }
+
+    /// reconstruction error of the stored vectors, measured on at most sample of them
+    /// spread evenly over the ids. vectors without a raw copy in their environment are skipped
+    pub fn quantization_report(&self, sample: usize) -> KathleenResult<QuantizationReport> {
+        if !self.model.is_trained() {
+            return Err(KathleenError::NotTrained);
+        }
+        let mut ids = self.locations.keys().copied().collect::<Vec<u32>>();
+        ids.sort();
+        let step = ids.len().div_ceil(sample.max(1)).max(1);
+        let metric = self.params().metric();
+        let mut pairs = Vec::new();
+        for vec_id in ids.into_iter().step_by(step) {
+            let cluster = self.locations[&vec_id];
+            let Some(raw) = self.db.load_vector(cluster, vec_id)? else {
+                continue;
+            };
+            // cosine indexes quantize the unit vectors
+            pairs.push((metric.prepare(&raw), self.decode(self.entry(cluster, vec_id).get_code(), cluster)?));
+        }
+        Ok(QuantizationReport::measure(&pairs, self.params().m()))
+    }
+
+    /// flushes the in-memory state to the database, the SDC tables only change with training which writes them
+    pub fn persist(&mut self) -> KathleenResult<()> {
+        self.db.persist_codebook(self.codebook.clone())?;
+        self.db.persist_pq_codebook(self.pq_codebook.clone())?;
+        self.db.persist_ivf(&self.ividx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array1;
+    use crate::ivfpq::{
+        ivfpq::AvlWrapper, metric::Metric, primitive_types::{IVListEntry, Segment},
+        test_utils::{fresh_db, repo_embedding, test_params, training_embeddings}
+    };
+
+    #[test]
+    fn add_returns_ids_that_survive_reopening() {
+        let path = fresh_db("./dbre_index_ids");
+        let params = test_params();
+        let embs = training_embeddings(&params);
+        let mut index = IvfPqIndex::open(path, params).unwrap();
+        let train_ids = index.train(&embs).unwrap();
+        let added = index.add(&embs[0]).unwrap();
+        assert!(!train_ids.contains(&added));
+        index.persist().unwrap();
+        drop(index);
+
+        let mut index = IvfPqIndex::open(path, params).unwrap();
+        let retrain_ids = index.train(&embs).unwrap();
+        assert!(retrain_ids.iter().all(|id| *id > added));
+    }
+
+    #[test]
+    fn retraining_re_encodes_stored_repos() {
+        let (mut index, embs) = trained_index("./dbre_index_retrain");
+        let params = *index.params();
+        let repo = repo_embedding(&params);
+        let vec_id = index.insert("rust-lang/rust", &repo).unwrap();
+
+        // trained on something else entirely, the codebooks have nothing in common with the first ones
+        let shifted = embs.iter()
+            .map(|emb| Embedding::from_base(Array1::from(emb.to_vec()) * 2. + 5., &params).unwrap())
+            .collect::<Vec<Embedding>>();
+        index.train(&shifted).unwrap();
+        let cluster = index.locations[&vec_id];
+        assert_eq!(index.locations.len(), 2 * embs.len() + 1);
+        // coded just like the same vector added under the new codebooks
+        let fresh = index.add(&repo).unwrap();
+        assert_eq!(index.locations[&fresh], cluster);
+        assert_eq!(index.entry(cluster, vec_id), index.entry(cluster, fresh));
+        index.remove(fresh).unwrap();
+
+        // the training repos around 40 end up with the same codes, it ties with them at the top
+        let hits = index.search(std::slice::from_ref(&repo), index.locations.len(), &all_lists(&index)).unwrap().remove(0);
+        let hit = hits.iter().find(|hit| hit.id == vec_id).unwrap();
+        assert_eq!(hit.key.as_deref(), Some("rust-lang/rust"));
+        let decoded = index.decode(index.entry(cluster, vec_id).get_code(), cluster).unwrap();
+        assert!((hit.distance - Metric::L2.embedding_distance(&repo, &decoded)).abs() < 1e-6);
+        assert!((hit.distance - hits[0].distance).abs() < 1e-9);
+
+        // the same after reopening, raw vectors included
+        drop(index);
+        let index = IvfPqIndex::open(Path::new("./dbre_index_retrain"), params).unwrap();
+        assert_eq!(index.locations[&vec_id], cluster);
+        assert_eq!(index.db.load_vector(cluster, vec_id).unwrap(), Some(repo.clone()));
+        let reopened = index.search(&[repo], index.locations.len(), &all_lists(&index)).unwrap().remove(0);
+        assert_eq!(reopened.iter().find(|hit| hit.id == vec_id), Some(hit));
+    }
+
+    #[test]
+    fn retraining_leaves_repos_without_raw_vectors_stale() {
+        let (mut index, embs) = trained_index("./dbre_index_retrain_codes");
+        // only the code is kept for this one, as by the versions before environments
+        let vec_id = index.db.next_id().unwrap();
+        index.place(vec_id, &embs[0]).unwrap();
+        index.persist().unwrap();
+        index.db.map_key("octocat/hello", vec_id).unwrap();
+        index.set_metadata(vec_id, &RepoMetadata { name: "octocat/hello".to_string(), ..RepoMetadata::default() }).unwrap();
+
+        index.train(&embs).unwrap();
+        assert_eq!(index.stale_ids(), vec![vec_id]);
+        assert!(!index.locations.contains_key(&vec_id));
+        assert_eq!(index.locations.len(), 2 * embs.len());
+
+        drop(index);
+        let mut index = IvfPqIndex::open(Path::new("./dbre_index_retrain_codes"), test_params()).unwrap();
+        assert_eq!(index.stale_ids(), vec![vec_id]);
+        assert!(!index.locations.contains_key(&vec_id));
+        assert_eq!(index.metadata_of(vec_id).unwrap().map(|metadata| metadata.name), Some("octocat/hello".to_string()));
+        assert_eq!(index.upsert_key("octocat/hello", &embs[0]).unwrap(), vec_id);
+        assert!(index.stale_ids().is_empty());
+        assert!(index.locations.contains_key(&vec_id));
+    }
+
+    #[test]
+    fn search_speaks_repo_names() {
+        let path = fresh_db("./dbre_index_keys");
+        let params = test_params();
+        let embs = training_embeddings(&params);
+        let mut index = IvfPqIndex::open(path, params).unwrap();
+        index.train(&embs).unwrap();
+        let repo = repo_embedding(&params);
+        let vec_id = index.insert("rust-lang/rust", &repo).unwrap();
+        assert!(index.insert("rust-lang/rust", &repo).is_err());
+        assert_eq!(index.id_of("rust-lang/rust").unwrap(), Some(vec_id));
+
+        let results = index.search(&[repo], 5, &SearchParams::default()).unwrap();
+        assert_eq!(results[0][0].key.as_deref(), Some("rust-lang/rust"));
+        // training embeddings were never given a key
+        assert!(results[0][1..].iter().all(|hit| hit.key.is_none()));
+    }
+
+    fn trained_index(path: &str) -> (IvfPqIndex, Vec<Embedding>) {
+        let params = test_params();
+        let embs = training_embeddings(&params);
+        let mut index = IvfPqIndex::open(fresh_db(path), params).unwrap();
+        index.train(&embs).unwrap();
+        (index, embs)
+    }
+
+    fn all_lists(index: &IvfPqIndex) -> SearchParams {
+        SearchParams { nprobe: index.params().nlist(), ..SearchParams::default() }
+    }
+
+    #[test]
+    fn deleted_repo_never_shows_up_again() {
+        let (mut index, _) = trained_index("./dbre_index_remove");
+        let params = *index.params();
+        let repo = repo_embedding(&params);
+        let vec_id = index.insert("rust-lang/rust", &repo).unwrap();
+        assert_eq!(index.search(std::slice::from_ref(&repo), 1, &SearchParams::default()).unwrap()[0][0].id, vec_id);
+
+        assert_eq!(index.remove_key("rust-lang/rust").unwrap(), Some(vec_id));
+        assert!(!index.remove(vec_id).unwrap());
+        assert_eq!(index.id_of("rust-lang/rust").unwrap(), None);
+        let results = index.search(std::slice::from_ref(&repo), 100, &all_lists(&index)).unwrap();
+        assert!(results[0].iter().all(|hit| hit.id != vec_id));
+
+        // neither does it come back after reopening the database
+        drop(index);
+        let index = IvfPqIndex::open(Path::new("./dbre_index_remove"), params).unwrap();
+        let results = index.search(&[repo], 100, &all_lists(&index)).unwrap();
+        assert!(results[0].iter().all(|hit| hit.id != vec_id));
+    }
+
+    #[test]
+    fn upsert_moves_the_repo_without_duplicates() {
+        let (mut index, embs) = trained_index("./dbre_index_upsert");
+        let vec_id = index.upsert_key("rust-lang/rust", &embs[0]).unwrap();
+        let moved = embs.last().unwrap().clone();
+        assert_eq!(index.upsert_key("rust-lang/rust", &moved).unwrap(), vec_id);
+
+        let results = index.search(std::slice::from_ref(&moved), 100, &all_lists(&index)).unwrap();
+        let hits = results[0].iter().filter(|hit| hit.id == vec_id).collect::<Vec<_>>();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].key.as_deref(), Some("rust-lang/rust"));
+        assert!(index.upsert(u32::MAX, &embs[0]).is_err());
+
+        // a rejected update leaves the repo as it was, in memory and on disk
+        let params = *index.params();
+        let wrong_dim = Embedding::new(vec![Segment::new(vec![1., 2.])]);
+        assert!(matches!(index.upsert(vec_id, &wrong_dim), Err(KathleenError::DimensionMismatch { .. })));
+        index.persist().unwrap();
+        let results = index.search(std::slice::from_ref(&moved), 100, &all_lists(&index)).unwrap();
+        assert_eq!(results[0].iter().filter(|hit| hit.id == vec_id).count(), 1);
+
+        // the move was written to disk as it happened
+        drop(index);
+        let index = IvfPqIndex::open(Path::new("./dbre_index_upsert"), params).unwrap();
+        let results = index.search(&[moved], 100, &all_lists(&index)).unwrap();
+        assert_eq!(results[0].iter().filter(|hit| hit.id == vec_id).count(), 1);
+    }
+
+    #[test]
+    fn failed_writes_leave_memory_as_the_database() {
+        let (mut index, embs) = trained_index("./dbre_index_failed_writes");
+        let vec_id = index.insert("rust-lang/rust", &embs[0]).unwrap();
+        let cluster = index.locations[&vec_id];
+        let moved = embs.last().unwrap().clone();
+        let target = index.model.predict(&moved).unwrap();
+        assert_ne!(cluster, target);
+
+        index.db.drop_environment(target).unwrap();
+        assert!(index.upsert(vec_id, &moved).is_err());
+        assert_eq!(index.locations[&vec_id], cluster);
+        assert!(index.ividx.get_cluster(cluster).get(&vec_id).is_some());
+        assert!(index.ividx.get_cluster(target).get(&vec_id).is_none());
+
+        index.db.drop_environment(cluster).unwrap();
+        assert!(index.remove(vec_id).is_err());
+        assert_eq!(index.locations[&vec_id], cluster);
+        assert!(index.ividx.get_cluster(cluster).get(&vec_id).is_some());
+    }
+
+    #[test]
+    fn reopened_index_is_searchable_right_away() {
+        let (mut index, embs) = trained_index("./dbre_index_model");
+        let params = *index.params();
+        drop(index);
+
+        index = IvfPqIndex::open(Path::new("./dbre_index_model"), params).unwrap();
+        let repo = repo_embedding(&params);
+        let vec_id = index.insert("rust-lang/rust", &repo).unwrap();
+        let results = index.search(&[repo], 1, &SearchParams::default()).unwrap();
+        assert_eq!(results[0][0].id, vec_id);
+        assert!(index.search(&embs[..1], 1, &SearchParams::default()).is_ok());
+    }
+
+    #[test]
+    fn refined_search_uses_stored_vectors() {
+        let (mut index, embs) = trained_index("./dbre_index_refine");
+        let params = *index.params();
+        let repo = repo_embedding(&params);
+        let vec_id = index.insert("rust-lang/rust", &repo).unwrap();
+        let moved = embs.last().unwrap().clone();
+        index.upsert(vec_id, &moved).unwrap();
+        drop(index);
+
+        // exact distances come from the environments, also after reopening
+        let index = IvfPqIndex::open(Path::new("./dbre_index_refine"), params).unwrap();
+        let refined = SearchParams { refine_factor: Some(4), ..all_lists(&index) };
+        let results = index.search(std::slice::from_ref(&moved), 3, &refined).unwrap();
+        assert_eq!(results[0].len(), 3);
+        assert_eq!(results[0][0].distance, 0.);
+        // the training embedding it was moved onto is just as close
+        assert!([vec_id, embs.len() as u32 - 1].contains(&results[0][0].id));
+        assert_eq!(results[0].iter().filter(|hit| hit.key.as_deref() == Some("rust-lang/rust")).count(), 1);
+    }
+
+    #[test]
+    fn hits_carry_repo_metadata() {
+        let (mut index, embs) = trained_index("./dbre_index_metadata");
+        let params = *index.params();
+        let metadata = RepoMetadata {
+            name: "rust-lang/rust".to_string(),
+            description: Some("Empowering everyone to build reliable and efficient software.".to_string()),
+            language: Some("Rust".to_string()),
+            license: Some("Apache-2.0".to_string()),
+            stars: 90000,
+            topics: vec!["compiler".to_string()]
+        };
+        let repo = repo_embedding(&params);
+        let vec_id = index.insert_with_metadata("rust-lang/rust", &repo, &metadata).unwrap();
+        let cargo = index.insert("rust-lang/cargo", &embs[0]).unwrap();
+        let results = index.search(std::slice::from_ref(&repo), 1, &SearchParams::default()).unwrap();
+        assert_eq!(results[0][0].id, vec_id);
+        assert_eq!(results[0][0].metadata.as_ref(), Some(&metadata));
+
+        // documents stay with the repo when it moves, and go away with it
+        index.upsert(vec_id, &embs[0]).unwrap();
+        assert_eq!(index.metadata_of(vec_id).unwrap(), Some(metadata.clone()));
+        assert_eq!(index.metadata_of(cargo).unwrap(), None);
+        index.set_metadata(cargo, &RepoMetadata { name: "rust-lang/cargo".to_string(), ..metadata }).unwrap();
+        assert!(index.set_metadata(u32::MAX, &RepoMetadata::default()).is_err());
+        index.remove(vec_id).unwrap();
+        drop(index);
+
+        let index = IvfPqIndex::open(Path::new("./dbre_index_metadata"), params).unwrap();
+        assert_eq!(index.metadata_of(vec_id).unwrap(), None);
+        assert_eq!(index.metadata_of(cargo).unwrap().map(|metadata| metadata.name), Some("rust-lang/cargo".to_string()));
+    }
+
+    #[test]
+    fn filtered_search_only_returns_matching_repos() {
+        let (mut index, embs) = trained_index("./dbre_index_filter");
+        let query = &embs[0];
+        let metadata = |name: &str, language: &str| RepoMetadata {
+            name: name.to_string(),
+            language: Some(language.to_string()),
+            stars: 500,
+            ..RepoMetadata::default()
+        };
+        // close to the query, but not in Rust
+        index.insert_with_metadata("golang/go", query, &metadata("golang/go", "Go")).unwrap();
+        index.insert_with_metadata("python/cpython", &embs[1], &metadata("python/cpython", "Python")).unwrap();
+        index.insert_with_metadata("torvalds/linux", &embs[2], &metadata("torvalds/linux", "C")).unwrap();
+        // in a list the query doesn't probe
+        let far = embs.last().unwrap();
+        assert_ne!(index.model.predict(far).unwrap(), index.model.predict(query).unwrap());
+        let rust = index.insert_with_metadata("rust-lang/rust", far, &metadata("rust-lang/rust", "Rust")).unwrap();
+
+        // selective enough to rank only the matching entries, wherever they are
+        let only_rust = SearchParams { filter: Some(Filter::Language("Rust".to_string())), ..SearchParams::default() };
+        let results = index.search(std::slice::from_ref(query), 5, &only_rust).unwrap();
+        assert_eq!(results[0].iter().map(|hit| hit.id).collect::<Vec<u32>>(), vec![rust]);
+
+        // negations have to look at the documents during the scan
+        let not_go = SearchParams { filter: Some(Filter::Not(Box::new(Filter::Language("Go".to_string())))), ..all_lists(&index) };
+        let results = index.search(std::slice::from_ref(query), 5, &not_go).unwrap();
+        let mut names = results[0].iter().map(|hit| hit.metadata.as_ref().unwrap().name.as_str()).collect::<Vec<&str>>();
+        names.sort();
+        assert_eq!(names, vec!["python/cpython", "rust-lang/rust", "torvalds/linux"]);
+
+        // broad filters are checked against the indexes while scanning
+        let starred = SearchParams { filter: Some(Filter::min_stars(1)), ..SearchParams::default() };
+        let results = index.search(std::slice::from_ref(query), 5, &starred).unwrap();
+        let mut names = results[0].iter().map(|hit| hit.metadata.as_ref().unwrap().name.as_str()).collect::<Vec<&str>>();
+        names.sort();
+        assert_eq!(names, vec!["golang/go", "python/cpython", "torvalds/linux"]);
+        assert!(search(&index.ividx, std::slice::from_ref(query), &index.codebook, &index.pq_codebook, 5, &starred).is_err());
+    }
+
+    #[test]
+    fn set_queries_leave_the_query_repos_out() {
+        let (mut index, embs) = trained_index("./dbre_index_set");
+        // two repos of the same cluster, the third embedding of it should come first
+        let a = index.insert("rust-lang/rust", &embs[0]).unwrap();
+        let b = index.insert("rust-lang/cargo", &embs[1]).unwrap();
+        let params = all_lists(&index);
+        for aggregation in [Aggregation::MeanDistance, Aggregation::MaxDistance, Aggregation::ReciprocalRank, Aggregation::Centroid] {
+            let hits = index.search_similar_to(&["rust-lang/rust", "rust-lang/cargo"], 4, aggregation, &params).unwrap();
+            assert_eq!(hits.len(), 4, "{aggregation:?}");
+            assert!(hits.iter().all(|hit| hit.id != a && hit.id != b), "{aggregation:?}");
+            // the training copies of the queries and the rest of their cluster
+            let mut ids = hits[..3].iter().map(|hit| hit.id).collect::<Vec<u32>>();
+            ids.sort();
+            assert_eq!(ids, vec![0, 1, 2], "{aggregation:?}");
+        }
+        // the queries a set query fans out to don't count as clusters queried together
+        assert!(index.cluster_graph().is_empty());
+        let refined = SearchParams { refine_factor: Some(2), ..params.clone() };
+        let hits = index.search_set(&embs[..2], &[0, 1, a, b], 2, Aggregation::MaxDistance, &refined).unwrap();
+        assert_eq!(hits[0].id, 2);
+        assert!(index.search_similar_to(&["rust-lang/rustc"], 4, Aggregation::Centroid, &params).is_err());
+        assert!(index.search_set(&[], &[], 4, Aggregation::MeanDistance, &params).is_err());
+    }
+
+    #[test]
+    fn searches_link_the_clusters_they_cross() {
+        let (mut index, embs) = trained_index("./dbre_index_graph");
+        let params = *index.params();
+        let query = &embs[0];
+        let origin = index.model.predict(query).unwrap();
+        assert!(index.cluster_graph().is_empty());
+
+        let results = index.search(std::slice::from_ref(query), 40, &all_lists(&index)).unwrap();
+        let crossed = results[0].iter()
+            .map(|hit| hit.cluster)
+            .filter(|cluster| *cluster != origin)
+            .collect::<HashSet<Clusters>>();
+        assert!(!crossed.is_empty());
+        index.search(std::slice::from_ref(query), 40, &all_lists(&index)).unwrap();
+        let graph = index.cluster_graph();
+        assert_eq!(graph.edges().count(), crossed.len());
+        for cluster in &crossed {
+            let edge = graph.edge(origin, *cluster).unwrap();
+            assert_eq!(edge.queries, 2);
+            assert!(edge.strength > 0. && edge.strength <= 2.);
+        }
+        let (_, path) = graph.shortest_path(origin, *crossed.iter().next().unwrap()).unwrap();
+        assert_eq!(path[0], origin);
+
+        drop(index);
+        index = IvfPqIndex::open(Path::new("./dbre_index_graph"), params).unwrap();
+        assert_eq!(index.cluster_graph(), graph);
+        index.train(&embs).unwrap();
+        assert!(index.cluster_graph().is_empty());
+    }
+
+    #[test]
+    fn station_search_stays_in_the_meeting_cluster() {
+        let (mut index, embs) = trained_index("./dbre_index_station");
+        let first = index.model.predict(&embs[0]).unwrap();
+        let other = embs.iter().position(|emb| index.model.predict(emb).unwrap() != first).unwrap();
+        let a = index.insert("rust-lang/rust", &embs[0]).unwrap();
+        let b = index.insert("golang/go", &embs[other]).unwrap();
+        let station = |index: &IvfPqIndex, criterion| index.search_station(&["rust-lang/rust", "golang/go"], 3, criterion, &SearchParams::default()).unwrap();
+        // nothing links both clusters yet
+        assert_eq!(station(&index, StationCriterion::MinMax), None);
+        assert!(index.search_station(&["rust-lang/rust", "nobody/nothing"], 3, StationCriterion::MinMax, &SearchParams::default()).is_err());
+
+        index.search(&[embs[0].clone(), embs[other].clone()], 40, &all_lists(&index)).unwrap();
+        for criterion in [StationCriterion::MinMax, StationCriterion::MinSum] {
+            let found = station(&index, criterion).unwrap();
+            let expected = index.cluster_graph().station(&[index.locations[&a], index.locations[&b]], criterion).unwrap();
+            assert_eq!((found.cluster, found.distance), expected);
+            assert!(!found.hits.is_empty() && found.hits.len() <= 3);
+            assert!(found.hits.iter().all(|hit| hit.cluster == found.cluster && hit.id != a && hit.id != b));
+            assert!(found.hits.windows(2).all(|pair| pair[0].distance <= pair[1].distance));
+        }
+    }
+
+    #[test]
+    fn user_searches_link_home_to_results() {
+        let (mut index, embs) = trained_index("./dbre_index_users");
+        let params = *index.params();
+        let home = index.model.predict(&embs[0]).unwrap();
+        let own = embs.iter().filter(|emb| index.model.predict(emb).unwrap() == home).take(3).cloned().collect::<Vec<Embedding>>();
+        let away = embs.iter().find(|emb| index.model.predict(emb).unwrap() != home).unwrap().clone();
+        let stray = index.model.predict(&away).unwrap();
+        let mut repos = own.clone();
+        repos.push(away.clone());
+        let profile = index.set_user("octocat", &repos).unwrap();
+        assert_eq!(profile, UserProfile { login: "octocat".to_string(), home, repos: 4 });
+        assert!(index.set_user("nobody", &[]).is_err());
+        assert!(index.search_as("nobody", std::slice::from_ref(&away), 5, &SearchParams::default()).is_err());
+
+        // the query falls far from home, the path still starts there
+        let results = index.search_as("octocat", std::slice::from_ref(&away), 5, &SearchParams::default()).unwrap();
+        assert!(results[0].iter().all(|hit| hit.cluster == stray));
+        let graph = index.cluster_graph();
+        assert_eq!(graph.edges().count(), 1);
+        let edge = graph.edge(home, stray).unwrap();
+        // the only cluster crossed into is the one with the best hit
+        assert_eq!((edge.queries, edge.strength), (1, 1.));
+
+        drop(index);
+        index = IvfPqIndex::open(Path::new("./dbre_index_users"), params).unwrap();
+        assert_eq!(index.user("octocat").unwrap().map(|profile| profile.home), Some(home));
+        index.train(&embs).unwrap();
+        assert_eq!(index.user("octocat").unwrap(), None);
+    }
+
+    #[test]
+    fn cosine_indexes_rank_by_angle() {
+        let path = fresh_db("./dbre_index_cosine");
+        let params = test_params().with_metric(Metric::Cosine);
+        let embs = training_embeddings(&params);
+        let mut index = IvfPqIndex::open(path, params).unwrap();
+        index.train(&embs).unwrap();
+        // same direction as a stored repo, ten times as long
+        let query = Embedding::from_base(Array1::from(embs[5].to_vec()) * 10., &params).unwrap();
+        let refined = SearchParams { refine_factor: Some(4), ..all_lists(&index) };
+        let hits = index.search(std::slice::from_ref(&query), 5, &refined).unwrap().remove(0);
+        assert_eq!(hits[0].id, 5);
+        assert!(hits[0].distance.abs() < 1e-9);
+        for hit in &hits {
+            let exact = Metric::Cosine.embedding_distance(&query, &embs[hit.id as usize]);
+            assert!((hit.distance - exact).abs() < 1e-9);
+        }
+        assert!((Metric::Cosine.score(hits[0].distance) - 1.).abs() < 1e-9);
+
+        // the metric is part of what the index was created with
+        drop(index);
+        assert!(IvfPqIndex::open(path, test_params()).is_err());
+        assert!(IvfPqIndex::open(path, params).is_ok());
+    }
+
+    #[test]
+    fn quantization_error_gets_reported() {
+        let path = fresh_db("./dbre_index_quantization");
+        let mut index = IvfPqIndex::open(path, test_params()).unwrap();
+        assert!(matches!(index.quantization_report(10), Err(KathleenError::NotTrained)));
+        let embs = training_embeddings(index.params());
+        let ids = index.train(&embs).unwrap();
+
+        let report = index.quantization_report(usize::MAX).unwrap();
+        assert_eq!(report.vectors, embs.len());
+        assert_eq!(report.subspaces.len(), index.params().m());
+        let summed = report.subspaces.iter().map(|stats| stats.mean).sum::<f64>();
+        assert!((report.total.mean - summed).abs() < 1e-9);
+        for stats in report.subspaces.iter().chain([&report.total]) {
+            assert!(0. <= stats.p50 && stats.p50 <= stats.p90 && stats.p90 <= stats.p99 && stats.p99 <= stats.max);
+        }
+        // the first training vector is among the measured ones
+        let cluster = index.locations[&ids[0]];
+        let decoded = index.decode(index.entry(cluster, ids[0]).get_code(), cluster).unwrap();
+        let error = Metric::SquaredL2.embedding_distance(&embs[0], &decoded);
+        assert!(error <= report.total.max);
+        assert_eq!(index.quantization_report(5).unwrap().vectors, 5);
+    }
+
+    #[test]
+    fn stored_repos_are_searchable_without_their_raw_vector() {
+        let (mut index, embs) = trained_index("./dbre_index_sdc");
+        let params = all_lists(&index);
+        // with a raw vector the repo itself is left out of its own results
+        let with_raw = index.insert("rust-lang/rust", &embs[0]).unwrap();
+        let hits = index.search_by_id(with_raw, 5, &params).unwrap();
+        assert_eq!(hits.len(), 5);
+        assert!(hits.iter().all(|hit| hit.id != with_raw));
+        assert!(index.cluster_graph().is_empty());
+
+        // only the code is kept for this one
+        let vec_id = 1000;
+        let cluster = index.place(vec_id, &embs[3]).unwrap();
+        index.persist().unwrap();
+        let code = index.entry(cluster, vec_id).get_code().clone();
+        let decoded = index.decode(&code, cluster).unwrap();
+        let hits = index.search_by_id(vec_id, 4, &params).unwrap();
+        assert_eq!(hits.len(), 4);
+        assert!(hits.iter().all(|hit| hit.id != vec_id));
+        assert!(hits.windows(2).all(|pair| pair[0].distance <= pair[1].distance));
+        for hit in &hits {
+            let other = index.decode(index.entry(hit.cluster, hit.id).get_code(), hit.cluster).unwrap();
+            assert!((hit.distance - Metric::L2.embedding_distance(&decoded, &other)).abs() < 1e-6);
+        }
+        assert_eq!(hits[0].key, None);
+
+        // the tables come back with the index
+        let index_params = *index.params();
+        drop(index);
+        let index = IvfPqIndex::open(Path::new("./dbre_index_sdc"), index_params).unwrap();
+        assert_eq!(index.search_by_id(vec_id, 4, &params).unwrap(), hits);
+        assert!(matches!(index.search_by_id(u32::MAX, 4, &params), Err(KathleenError::NotFound(_))));
+    }
+
+    #[test]
+    fn untrained_index_refuses_inserts() {
+        let params = test_params();
+        let mut index = IvfPqIndex::open(fresh_db("./dbre_index_untrained"), params).unwrap();
+        let embs = training_embeddings(&params);
+        assert!(matches!(index.add(&embs[0]), Err(KathleenError::NotTrained)));
+
+        // a coarse codebook alone, as the first versions left it
+        index.db.persist_codebook(vec![embs[0].clone(); params.nlist()]).unwrap();
+        drop(index);
+        let mut index = IvfPqIndex::open(Path::new("./dbre_index_untrained"), params).unwrap();
+        assert!(matches!(index.add(&embs[0]), Err(KathleenError::NotTrained)));
+    }
+
+    #[test]
+    fn ids_skip_entries_stored_before_the_counter() {
+        let path = fresh_db("./dbre_index_legacy");
+        let params = test_params();
+        {
+            // index written by the old process-local allocator
+            let mut db = DatabaseWrapper::open(path, &params).unwrap();
+            let mut ividx = InvertedIndex::empty(params);
+            for cluster in 0..params.nlist() as u32 {
+                let mut avl = AvlWrapper::new();
+                avl.insert(500 + cluster, Box::new(IVListEntry::new(vec![1; params.m()], cluster)));
+                *ividx.get_cluster_mut(cluster) = avl;
+            }
+            db.persist_ivf(&ividx).unwrap();
+            // training re-encodes them from these
+            let zeros = Embedding::zeros(&params);
+            db.persist_vectors((0..params.nlist() as u32).map(|cluster| (cluster, 500 + cluster, &zeros))).unwrap();
+        }
+        let mut index = IvfPqIndex::open(path, params).unwrap();
+        let ids = index.train(&training_embeddings(&params)).unwrap();
+        assert!(ids.iter().all(|id| *id >= 500 + params.nlist() as u32));
+    }
+
+    #[test]
+    fn legacy_codes_wait_for_new_embeddings() {
+        let path = fresh_db("./dbre_index_stale");
+        let params = test_params();
+        {
+            // the first layout, codes without the PQ codebook they came from
+            let mut options = rocksdb::Options::default();
+            options.create_if_missing(true);
+            let db = rocksdb::DB::open(&options, path).unwrap();
+            let mut lists = vec!["{}".to_string(); params.nlist()];
+            lists[2] = "{7: [1, 1, 1, 1];2\n9: [0, 3, 3, 1];2\n}".to_string();
+            db.put(b"ivf", serde_cbor::to_vec(&lists).unwrap()).unwrap();
+        }
+        let mut index = IvfPqIndex::open(path, params).unwrap();
+        assert_eq!(index.stale_ids(), vec![7, 9]);
+        assert!(index.locations.is_empty());
+        index.db.map_key("octocat/hello", 9).unwrap();
+        index.set_metadata(9, &RepoMetadata { name: "octocat/hello".to_string(), ..RepoMetadata::default() }).unwrap();
+
+        let embs = training_embeddings(&params);
+        let ids = index.train(&embs).unwrap();
+        assert!(ids.iter().all(|id| *id > 9));
+        assert_eq!(index.stale_ids(), vec![7, 9]);
+        assert_eq!(index.metadata_of(9).unwrap().map(|metadata| metadata.name), Some("octocat/hello".to_string()));
+
+        // searchable again once upserted
+        index.upsert(7, &embs[0]).unwrap();
+        assert_eq!(index.stale_ids(), vec![9]);
+        let hits = index.search(&embs[..1], index.locations.len(), &all_lists(&index)).unwrap().remove(0);
+        assert!(hits.iter().any(|hit| hit.id == 7));
+        assert!(hits.iter().all(|hit| hit.id != 9));
+        assert_eq!(index.remove_key("octocat/hello").unwrap(), Some(9));
+        assert!(index.stale_ids().is_empty());
+        assert_eq!(index.metadata_of(9).unwrap(), None);
+
+        drop(index);
+        let index = IvfPqIndex::open(path, params).unwrap();
+        assert!(index.stale_ids().is_empty());
+        assert_eq!(index.db.load_vector(index.locations[&7], 7).unwrap(), Some(embs[0].clone()));
+    }
+}
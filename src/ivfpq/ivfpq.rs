@@ -2,30 +2,87 @@ use avl::map::AvlTreeMap;
 use linfa::{traits::Fit, DatasetBase};
 use ndarray::{Array2, Array1};
 use serde::{Serialize, Deserialize};
-use std::ops::{Deref, DerefMut};
+use std::{collections::{HashMap, hash_map::Entry}, ops::{Deref, DerefMut}};
 use ordered_float::NotNan;
-use std::marker::PhantomData;
 
 use super::{
     maxheap_wrapper::{BinaryHeapWrapper, HeapNode},
-    primitive_types::{Embedding, Clusters, IVListEntry, DistanceTable, Codebook}
+    primitive_types::{Embedding, Segment, Clusters, CodeWord, IVListEntry, DistanceTable, Codebook, PqCode, PqCodebook, SearchHit},
+    filter::Filter,
+    metric::Metric,
+    sdc::SdcTables,
+    error::{KathleenError, KathleenResult}
 };
 use linfa_clustering;
-use linfa::{self, prelude::Predict};
-use linfa_nn::distance::{L2Dist, Distance};
+use linfa_nn::distance::L2Dist;
 // k's between subspaces k-means and coarse quantizer may differ, take it into account
 
-pub const EMBEDDING_DIM: usize = 12;
-pub const EMBEDDING_M_SEGMENTS: usize = 4;
-/// EMBEDDING_DIM % EMBEDDING_M_SEGMENTS == 0
-pub const SEGMENT_DIM: usize = EMBEDDING_DIM / EMBEDDING_M_SEGMENTS;
-pub const CENTROIDS_PER_SUBSPACE_CLUSTER: usize = 8;
-pub const K_MAX_CENTROIDS: usize = EMBEDDING_M_SEGMENTS * CENTROIDS_PER_SUBSPACE_CLUSTER;
-pub const RETRIEVE_KNN: usize = 10;
-pub const CQ_K_CENTROIDS: usize = 8;
-pub const CODE_SIZE: usize = 1;
 pub const EMBEDDINGS_PER_CLUSTER: usize = 3;
 
+/// Geometry of an index, chosen when the index is created and stored along with it
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(try_from = "RawIndexParams")]
+pub struct IndexParams {
+    /// dimensionality of the raw embeddings
+    dim: usize,
+    /// number of segments (subspaces) every embedding is split into
+    m: usize,
+    /// centroids per subspace quantizer
+    ks: usize,
+    /// centroids of the coarse quantizer, i.e. number of inverted lists
+    nlist: usize,
+    /// how vectors are compared, indexes stored before metrics existed are L2
+    #[serde(default)]
+    metric: Metric
+}
+
+impl IndexParams {
+    /// dim % m == 0 and ks must fit in a code word
+    pub fn new(dim: usize, m: usize, ks: usize, nlist: usize) -> KathleenResult<Self> {
+        if dim == 0 || m == 0 || ks == 0 || nlist == 0 {
+            return Err(KathleenError::InvalidParams("index parameters must be greater than zero".to_string()));
+        }
+        if dim % m != 0 {
+            return Err(KathleenError::InvalidParams(format!("embedding dimension {dim} is not divisible by {m} segments")));
+        }
+        if ks > CodeWord::MAX as usize + 1 {
+            return Err(KathleenError::InvalidParams(format!("{ks} centroids per subspace do not fit in a {}-bit code", CodeWord::BITS)));
+        }
+        Ok(Self { dim, m, ks, nlist, metric: Metric::default() })
+    }
+
+    pub fn with_metric(self, metric: Metric) -> Self {
+        Self { metric, ..self }
+    }
+
+    pub fn dim(&self) -> usize { self.dim }
+    pub fn m(&self) -> usize { self.m }
+    pub fn ks(&self) -> usize { self.ks }
+    pub fn nlist(&self) -> usize { self.nlist }
+    pub fn segment_dim(&self) -> usize { self.dim / self.m }
+    pub fn metric(&self) -> Metric { self.metric }
+}
+
+/// IndexParams as stored, a record that doesn't pass IndexParams::new doesn't load
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct RawIndexParams {
+    dim: usize,
+    m: usize,
+    ks: usize,
+    nlist: usize,
+    #[serde(default)]
+    metric: Metric
+}
+
+impl TryFrom<RawIndexParams> for IndexParams {
+    type Error = KathleenError;
+
+    fn try_from(raw: RawIndexParams) -> KathleenResult<Self> {
+        Ok(Self::new(raw.dim, raw.m, raw.ks, raw.nlist)?.with_metric(raw.metric))
+    }
+}
+
 /// holds tuple (cluster_no, embedding)
 pub struct Centroid<'a> ((Clusters, &'a Embedding));
 
@@ -44,12 +101,13 @@ impl AvlWrapper {
     // get all the elements in-order
     pub fn get_all(&self) -> Vec<&Box<IVListEntry>> {
         self.0.iter()
-            .map(|(k, v)| v)
+            .map(|(_, v)| v)
             .collect::<Vec<&Box<IVListEntry>>>() 
     }
 
-    pub fn add_embedding(&mut self, emb: &Embedding, cluster: Clusters, vec_id: u32, cb: &Codebook) {
-        let code = emb.encode(cb);
+    /// stores the PQ code of the embedding's residual with respect to the cluster centroid (IVFADC)
+    pub fn add_embedding(&mut self, emb: &Embedding, centroid: &Embedding, cluster: Clusters, vec_id: u32, pq_cb: &PqCodebook) {
+        let code = emb.residual(centroid).encode(pq_cb);
         let entry = IVListEntry::new(
             code,
             cluster
@@ -72,76 +130,134 @@ impl DerefMut for AvlWrapper {
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
-pub struct InvertedIndex(Vec<AvlWrapper>);
+pub struct InvertedIndex {
+    params: IndexParams,
+    lists: Vec<AvlWrapper>
+}
 
 impl InvertedIndex {
-    pub fn empty() -> Self {
-        let mut ividx = Vec::with_capacity(CQ_K_CENTROIDS);
-        for _ in 0..CQ_K_CENTROIDS {
+    pub fn empty(params: IndexParams) -> Self {
+        let mut ividx = Vec::with_capacity(params.nlist());
+        for _ in 0..params.nlist() {
             let wrapper = AvlWrapper::new();
             ividx.push(wrapper);
         }
-        Self(ividx)
+        Self { params, lists: ividx }
+    }
+
+    pub fn params(&self) -> &IndexParams {
+        &self.params
     }
 
     pub fn push(&mut self, value: AvlWrapper) {
-        self.0.push(value)
+        self.lists.push(value)
     }
     
     pub fn get_cluster(&self, clust_no: Clusters) ->  &AvlWrapper {
-          &self.0[clust_no as usize] 
+          &self.lists[clust_no as usize] 
     }
     pub fn get_cluster_mut(&mut self, clust_no: Clusters) ->  &mut AvlWrapper {
-          &mut self.0[clust_no as usize] 
+          &mut self.lists[clust_no as usize] 
     }
 
-    /// Table containing distance to each list entry segment for every centroid in Codebook from every query vector segment
-    /// K x N table
+    /// Table containing distance from every query vector segment to each sub-centroid of that segment's subspace
+    /// ks x m table
     /// take from distance that is the lowest the formed codes what will give
-    pub fn compute_distance_table(query_vector: &Embedding, codebook: &Codebook) -> DistanceTable {
+    /// distances are taken segment by segment, so cosine makes no sense here, see list_distance_table
+    pub fn compute_distance_table(query_vector: &Embedding, pq_codebook: &PqCodebook, metric: Metric) -> DistanceTable {
         // compute distances
         let mut distance_table = vec![];
 
-        for centroid in codebook {
+        for centroid in 0..pq_codebook.ks() {
 
-            let c_segments = centroid.into_segments();
             let qv_segments = query_vector.into_segments();
 
-            let distances = c_segments
-                .zip(qv_segments)
-                .map(|(c_j, qv)| L2Dist::distance(&L2Dist, Array1::from(c_j.to_vec()).view(), Array1::from(qv.to_vec()).view()));
-            let distances = {
-                let mut tmp = [0.0; EMBEDDING_M_SEGMENTS];
-                distances.enumerate().for_each(|(ind, d)| tmp[ind] = d);
-                tmp
-            };
+            let distances = qv_segments
+                .enumerate()
+                .map(|(j, qv)| metric.segment_distance(&pq_codebook.subspace(j)[centroid], qv))
+                .collect::<Vec<f64>>();
             distance_table.push(distances);
         }
-        let distance_table = {
-            let mut tmp = [[0.0; EMBEDDING_M_SEGMENTS]; CENTROIDS_PER_SUBSPACE_CLUSTER];
-            distance_table.into_iter().enumerate().for_each(|(ind, dists)| tmp[ind] = dists);
-            tmp
-        };
         distance_table
     }
 
+    /// table to look up the codes of the list under centroid, plus the distance every one of its
+    /// entries adds on top of its code. the L2 family compares the query residual with the codes
+    /// through squared distances, the only ones that add up over the segments.
+    /// the inner product family splits <q, c + r> into <q, c> + <q, r>
+    pub fn list_distance_table(&self, query_vector: &Embedding, centroid: &Embedding, pq_codebook: &PqCodebook) -> KathleenResult<(DistanceTable, f64)> {
+        let metric = self.params.metric();
+        if !metric.is_inner_product() {
+            let resid = self.compute_residual(query_vector, centroid)?;
+            return Ok((Self::compute_distance_table(&resid, pq_codebook, metric.table_metric()), 0.));
+        }
+        let qv = metric.prepare(query_vector);
+        let offset = match metric {
+            Metric::Cosine => 1.,
+            _ => 0.
+        };
+        Ok((
+            Self::compute_distance_table(&qv, pq_codebook, Metric::InnerProduct),
+            offset + Metric::InnerProduct.embedding_distance(&qv, centroid)
+        ))
+    }
+
     /// retrieves the nearest neighbor for the requested query vector
-    pub fn get_nearest_centroid<'a>(&self, model: &Model, query_vector: &Embedding, codebook: &'a Codebook) -> Result<Centroid<'a>, String> {
+    pub fn get_nearest_centroid<'a>(&self, model: &Model, query_vector: &Embedding, codebook: &'a Codebook) -> KathleenResult<Centroid<'a>> {
        let predicted_cluster = model.predict(query_vector)?;
-       Ok(Centroid((predicted_cluster.clone(), &codebook[predicted_cluster as usize])))
+       Ok(Centroid((predicted_cluster, &codebook[predicted_cluster as usize])))
+    }
+
+    /// ranks every coarse centroid by its distance to the query vector and keeps the n closest
+    pub fn get_nearest_centroids<'a>(&self, query_vector: &Embedding, codebook: &'a Codebook, n: usize) -> Vec<Centroid<'a>> {
+        rank_centroids(query_vector, codebook, self.params.metric())
+            .into_iter()
+            .take(n)
+            .map(|(_, cluster)| Centroid((cluster, &codebook[cluster as usize])))
+            .collect()
     }
 
-    fn compute_residual(&self, v1: &Embedding, v2: &Embedding) -> Embedding {
+    /// v1 - v2, checked against the index dimension
+    fn compute_residual(&self, v1: &Embedding, v2: &Embedding) -> KathleenResult<Embedding> {
        let ndarray_emb1 =  Array1::from(v1.to_vec());
        let ndarray_emb2 =  Array1::from(v2.to_vec());
        Embedding::from_base(
-           ndarray_emb1 - ndarray_emb2
+           ndarray_emb1 - ndarray_emb2,
+           &self.params
        )
     }
 
-    pub fn add_embedding_to_cluster(&mut self, cluster: Clusters, emb: &Embedding, cb: &Codebook) {
-        let avl: &mut AvlWrapper = self.0.get_mut(cluster as usize).unwrap();
-        avl.add_embedding(emb, cluster, next_id(), cb)
+    /// vec_id has to be unique across the whole index, see DatabaseWrapper::next_id
+    pub fn add_embedding_to_cluster(&mut self, cluster: Clusters, vec_id: u32, emb: &Embedding, cb: &Codebook, pq_cb: &PqCodebook) -> KathleenResult<()> {
+        if emb.dim() != self.params.dim() {
+            return Err(KathleenError::DimensionMismatch { expected: self.params.dim(), got: emb.dim() });
+        }
+        let avl: &mut AvlWrapper = self.lists.get_mut(cluster as usize)
+            .ok_or_else(|| KathleenError::NotFound(format!("cluster {cluster}")))?;
+        let centroid = cb.get(cluster as usize)
+            .ok_or_else(|| KathleenError::NotFound(format!("centroid of cluster {cluster}")))?;
+        avl.add_embedding(&self.params.metric().prepare(emb), centroid, cluster, vec_id, pq_cb);
+        Ok(())
+    }
+
+    /// takes the entry with vec_id out of the cluster's list
+    pub fn remove_from_cluster(&mut self, cluster: Clusters, vec_id: u32) -> Option<Box<IVListEntry>> {
+        self.lists.get_mut(cluster as usize)?.remove(&vec_id)
+    }
+
+    /// id -> cluster lookup of every stored entry
+    pub fn locations(&self) -> HashMap<u32, Clusters> {
+        self.lists.iter()
+            .enumerate()
+            .flat_map(|(cluster, avl)| avl.iter().map(move |(id, _)| (*id, cluster as Clusters)))
+            .collect()
+    }
+
+    /// highest vector id stored in any of the inverted lists
+    pub fn max_id(&self) -> Option<u32> {
+        self.lists.iter()
+            .filter_map(|avl| avl.iter().map(|(id, _)| *id).max())
+            .max()
     }
 
 }
@@ -149,7 +265,7 @@ impl InvertedIndex {
 impl Deref for InvertedIndex {
     type Target = Vec<AvlWrapper>;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.lists
     }
 }
 
@@ -169,277 +285,614 @@ impl Deref for InvertedIndex {
 
 use linfa_clustering::KMeans;
 
+/// (distance, cluster) to every coarse centroid, nearest first
+fn rank_centroids(query_vector: &Embedding, codebook: &Codebook, metric: Metric) -> Vec<(f64, Clusters)> {
+    let qv = Array1::from(query_vector.to_vec());
+    let mut ranked = codebook.iter()
+        .enumerate()
+        .map(|(cluster, centroid)| {
+            let dist = metric.distance(Array1::from(centroid.to_vec()).view(), qv.view());
+            (dist, cluster as Clusters)
+        })
+        .collect::<Vec<(f64, Clusters)>>();
+    ranked.sort_by(|a, b| a.0.total_cmp(&b.0));
+    ranked
+}
+
 pub struct Model {
-  pub model: Option<KMeans<f64, L2Dist>>
+  pub model: Option<KMeans<f64, L2Dist>>,
+  // centroids assignments are made against, either just fitted or rebuilt from a stored codebook
+  centroids: Option<Codebook>,
+  // what "nearest" means when assigning, taken from the index params on training
+  metric: Metric
 }
 
 impl Model {
-    pub fn new() -> Self {Self{model: None}}
-    pub fn predict(&self, qv: &Embedding) -> Result<Clusters, String> {
-       match &self.model {
-           Some(m) => {
-               let obs = DatasetBase::from(Array1::from(qv.to_vec()));
-               Ok(m.predict(&obs) as Clusters)
+    pub fn new() -> Self {Self{model: None, centroids: None, metric: Metric::default()}}
+
+    /// nearest-centroid assigner equivalent to the k-means that produced the codebook,
+    /// so a reopened index can place vectors without retraining
+    pub fn from_codebook(codebook: Codebook, metric: Metric) -> Self {
+        Self { model: None, centroids: Some(codebook), metric }
+    }
+
+    pub fn is_trained(&self) -> bool {
+        self.centroids.is_some()
+    }
+
+    pub fn predict(&self, qv: &Embedding) -> KathleenResult<Clusters> {
+       match &self.centroids {
+           Some(cb) => {
+               let expected = cb.first().map(|c| c.dim()).unwrap_or(0);
+               if qv.dim() != expected {
+                   return Err(KathleenError::DimensionMismatch { expected, got: qv.dim() });
+               }
+               rank_centroids(qv, cb, self.metric)
+                   .first()
+                   .map(|(_, cluster)| *cluster)
+                   .ok_or(KathleenError::NotTrained)
            },
-           None => Err("model not trained".to_string())
+           None => Err(KathleenError::NotTrained)
        }
     }
-    pub fn k_means(&mut self, ividx: &mut InvertedIndex, embs: &[Embedding]) -> Codebook {
+    /// trains the coarse quantizer on the raw embeddings and the product quantizer on their residuals,
+    /// then fills the inverted index with the training embeddings, stored under the given ids.
+    /// cosine indexes train on the embeddings scaled to unit length
+    pub fn k_means(&mut self, ividx: &mut InvertedIndex, embs: &[Embedding], ids: &[u32]) -> KathleenResult<(Codebook, PqCodebook)> {
+        if embs.len() != ids.len() {
+            return Err(KathleenError::InvalidParams(format!("got {} embeddings but {} ids", embs.len(), ids.len())));
+        }
         use rand_xoshiro::Xoshiro256Plus;
         use rand_xoshiro::rand_core::SeedableRng;
         let seed = 42;
         let rng = Xoshiro256Plus::seed_from_u64(seed);
-        let mut data = Array2::zeros((embs.len(), SEGMENT_DIM*EMBEDDING_M_SEGMENTS));
+        let params = *ividx.params();
+        self.metric = params.metric();
+        let embs = &embs.iter().map(|emb| self.metric.prepare(emb)).collect::<Vec<Embedding>>();
+        let mut data = Array2::zeros((embs.len(), params.dim()));
         for ind in 0..embs.len() {
             let emb = embs[ind].to_vec();
-            for each in 0..SEGMENT_DIM*EMBEDDING_M_SEGMENTS {
+            if emb.len() != params.dim() {
+                return Err(KathleenError::DimensionMismatch { expected: params.dim(), got: emb.len() });
+            }
+            for each in 0..params.dim() {
                 data[[ind, each]] = emb[each];
             }
         }
         let obs = DatasetBase::from(data);
         
-        let model = 
-            match &self.model {
-                Some(m) => m,
-                None => { self.model = Some(KMeans::params_with_rng(CQ_K_CENTROIDS, rng)
-                    .fit(&obs)
-                    .expect("KMeans fitted"));
-                self.model.as_ref().unwrap()}
-        };
+        // a previous training means nothing for these embeddings, and neither do the codes it left in the lists
+        let model = self.model.insert(KMeans::params_with_rng(params.nlist(), rng)
+            .fit(&obs)
+            .map_err(|e| KathleenError::Training(e.to_string()))?);
+        *ividx = InvertedIndex::empty(params);
 
         // create codebook
         let codebook = model.centroids();
-        let codebook: Codebook = {
-            let mut tmp: Codebook = [Embedding::default(); CQ_K_CENTROIDS];
-            codebook.rows().into_iter().enumerate().for_each(|(ind, emb)| tmp[ind] = Embedding::from_base(emb.to_owned()));
-            tmp
-        };
+        let codebook: Codebook = codebook.rows()
+            .into_iter()
+            .map(|emb| Embedding::from_base(emb.to_owned(), &params))
+            .collect::<KathleenResult<Codebook>>()?;
+        self.centroids = Some(codebook.clone());
+        // predict the cluster each embedding belongs to, as later insertions will
+        let pred_clusters = embs.iter()
+            .map(|emb| self.predict(emb))
+            .collect::<KathleenResult<Vec<Clusters>>>()?;
+        // subspace quantizers are trained on what the coarse quantizer leaves out
+        let residuals = embs.iter()
+            .zip(&pred_clusters)
+            .map(|(emb, cluster)| ividx.compute_residual(emb, &codebook[*cluster as usize]))
+            .collect::<KathleenResult<Vec<Embedding>>>()?;
+        let pq_codebook = Self::train_pq_codebook(&residuals, &params)?;
         // save it in the ividx
-        // for each embedding
-        for emb in embs {
-            // predict the cluster it belongs to
-            let new_obs = DatasetBase::from(Array1::from(emb.to_vec()));
-            let pred_cluster: Clusters = model.predict(&new_obs) as Clusters;
+        for ((emb, vec_id), pred_cluster) in embs.iter().zip(ids).zip(pred_clusters) {
             // add it to the predicted ividx entry
-            ividx.add_embedding_to_cluster(pred_cluster, emb, &codebook)
+            ividx.add_embedding_to_cluster(pred_cluster, *vec_id, emb, &codebook, &pq_codebook)?;
         }
         // save the model somehow (static or return it)
-        codebook
+        Ok((codebook, pq_codebook))
+    }
+
+    /// one k-means with ks centroids per subspace, fitted on the j-th segment of every vector
+    fn train_pq_codebook(vectors: &[Embedding], params: &IndexParams) -> KathleenResult<PqCodebook> {
+        use rand_xoshiro::Xoshiro256Plus;
+        use rand_xoshiro::rand_core::SeedableRng;
+        let seed = 42;
+        let segments = vectors.iter()
+            .map(|v| v.into_segments().collect::<Vec<&Segment>>())
+            .collect::<Vec<Vec<&Segment>>>();
+        let mut sub_centroids = Vec::with_capacity(params.m());
+        for j in 0..params.m() {
+            let mut data = Array2::zeros((vectors.len(), params.segment_dim()));
+            for (ind, segs) in segments.iter().enumerate() {
+                for (each, value) in segs[j].to_vec().into_iter().enumerate() {
+                    data[[ind, each]] = value;
+                }
+            }
+            let obs = DatasetBase::from(data);
+            let rng = Xoshiro256Plus::seed_from_u64(seed + j as u64);
+            let subq: KMeans<f64, L2Dist> = KMeans::params_with_rng(params.ks(), rng)
+                .fit(&obs)
+                .map_err(|e| KathleenError::Training(format!("subspace {j}: {e}")))?;
+            sub_centroids.push(
+                subq.centroids().rows().into_iter().map(|c| Segment::new(c.to_vec())).collect::<Vec<Segment>>()
+            );
+        }
+        Ok(PqCodebook::new(sub_centroids))
     }
 
 } 
 
-fn next_id() -> u32 {
-    static mut ID: u32 = 1;
-    unsafe {
-        ID += 1;
-        ID
+/// knobs of a single search request
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchParams {
+    /// number of inverted lists scanned for every query vector, nearest centroids first
+    pub nprobe: usize,
+    /// when set, k x refine_factor PQ candidates get re-ranked by the exact distance of the index metric on their raw vectors
+    pub refine_factor: Option<usize>,
+    /// only repos whose metadata matches make it into the results,
+    /// needs the documents so it's only honored by IvfPqIndex::search
+    pub filter: Option<Filter>,
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        Self { nprobe: 1, refine_factor: None, filter: None }
     }
-    
 }
 
-pub fn search<'a>(ividx: &'a InvertedIndex, query_vectors: &[Embedding], codebook: &Codebook, model: &Model) -> Result<Vec<Vec<HeapNode<'a>>>, String> {
-    
-    // this are centroids from the original coarse quantizer trained with raw vectors
-    // this is used just to know which cluster does each query_vector belongs to
-    let cq_nearest_centroids = query_vectors
-        .iter()
-        .map(|emb| /* this should be a call to the coarse quantizer */ ividx.get_nearest_centroid(model, emb, codebook).expect("Error getting nearest centroid: "))
-        .collect::<Vec<Centroid>>();
+impl SearchParams {
+    /// how many PQ candidates have to be collected to return k hits
+    pub fn candidates(&self, k: usize) -> usize {
+        self.refine_factor.map_or(k, |factor| k.saturating_mul(factor))
+    }
+}
 
-    
-    let residuals = cq_nearest_centroids
-        .iter()
-        .zip(query_vectors)
-        .map(|(cent, qv)| ividx.compute_residual(cent.0.1, qv))
-        .collect::<Vec<Embedding>>();
+fn validate_search(ividx: &InvertedIndex, query_vectors: &[Embedding], k: usize, params: &SearchParams) -> KathleenResult<()> {
+    if let Some(qv) = query_vectors.iter().find(|qv| qv.dim() != ividx.params().dim()) {
+        return Err(KathleenError::DimensionMismatch { expected: ividx.params().dim(), got: qv.dim() });
+    }
+    if k == 0 || params.nprobe == 0 || params.refine_factor == Some(0) {
+        return Err(KathleenError::InvalidParams("k, nprobe and refine_factor must be greater than zero".to_string()));
+    }
+    Ok(())
+}
+
+/// asymmetric distance of a pq code, looked up in the table of the list it belongs to.
+/// in table units, see Metric::reported
+fn adc_distance(dt: &DistanceTable, code: &PqCode) -> f64 {
+    code.iter()
+        .enumerate()
+        .map(|(subq, code)| dt[*code as usize][subq])
+        .sum::<f64>()
+}
+
+/// one list of at most k hits, nearest first, for every query vector
+pub fn search(ividx: &InvertedIndex, query_vectors: &[Embedding], codebook: &Codebook, pq_codebook: &PqCodebook, k: usize, params: &SearchParams) -> KathleenResult<Vec<Vec<SearchHit>>> {
+    if params.filter.is_some() {
+        return Err(KathleenError::InvalidParams("filters need the metadata documents, search through IvfPqIndex".to_string()));
+    }
+    search_filtered(ividx, query_vectors, codebook, pq_codebook, k, params, |_| Ok(true))
+}
+
+/// same as search, but entries whose id accept turns down are skipped before they reach the heap
+pub fn search_filtered<F>(ividx: &InvertedIndex, query_vectors: &[Embedding], codebook: &Codebook, pq_codebook: &PqCodebook, k: usize, params: &SearchParams, mut accept: F) -> KathleenResult<Vec<Vec<SearchHit>>>
+    where F: FnMut(u32) -> KathleenResult<bool> {
+    validate_search(ividx, query_vectors, k, params)?;
 
     let mut distance_results = Vec::new();
-    for (ind, resid) in residuals.iter().enumerate() {
-        let dt = InvertedIndex::compute_distance_table(resid, codebook);
-        let mut max_heap: BinaryHeapWrapper<HeapNode<'_>, {RETRIEVE_KNN}> = BinaryHeapWrapper::new();
-        let embs = ividx.get_cluster(cq_nearest_centroids[ind].0.0);
-        embs.get_all().iter()
-            .for_each(|entry| {
-                let emb_dist = entry.get_code().iter()
-                    .enumerate()
-                    .map(|(subq, code)| dt[*code as usize][subq] )
-                    .sum::<f64>();
-                if let Ok(distance) = NotNan::new(emb_dist) {
+    for qv in query_vectors {
+        // centroids from the original coarse quantizer trained with raw vectors,
+        // ranked so that the nprobe closest inverted lists get scanned
+        let probed_centroids = ividx.get_nearest_centroids(qv, codebook, params.nprobe);
+        let mut max_heap: BinaryHeapWrapper<HeapNode> = BinaryHeapWrapper::new(k);
+        for Centroid((cluster, centroid)) in probed_centroids {
+            // entries are encoded relative to their own centroid, so every list gets its own table
+            let (dt, offset) = ividx.list_distance_table(qv, centroid, pq_codebook)?;
+            for (vec_id, entry) in ividx.get_cluster(cluster).iter() {
+                if !accept(*vec_id)? {
+                    continue;
+                }
+                if let Ok(distance) = NotNan::new(offset + adc_distance(&dt, entry.get_code())) {
                     max_heap
-                        .push(HeapNode::new(distance, entry.get_code()))
+                        .push(HeapNode::new(distance, *vec_id, cluster))
                         .expect("Error while pushing distance to maxheap");
                 }
-            });
-        distance_results.push(max_heap.sorted());
+            }
+        }
+        distance_results.push(adc_hits(max_heap, ividx.params().metric()));
     }
     Ok(distance_results)
 }
 
+/// ranks only the given (id, cluster) entries, whatever list they are in.
+/// used when a selective filter already narrowed the candidates down, so no list has to be scanned
+pub fn search_entries(ividx: &InvertedIndex, query_vectors: &[Embedding], codebook: &Codebook, pq_codebook: &PqCodebook, k: usize, entries: &[(u32, Clusters)]) -> KathleenResult<Vec<Vec<SearchHit>>> {
+    validate_search(ividx, query_vectors, k, &SearchParams::default())?;
+
+    let mut distance_results = Vec::new();
+    for qv in query_vectors {
+        let mut tables: HashMap<Clusters, (DistanceTable, f64)> = HashMap::new();
+        let mut max_heap: BinaryHeapWrapper<HeapNode> = BinaryHeapWrapper::new(k);
+        for (vec_id, cluster) in entries {
+            let Some(entry) = ividx.get_cluster(*cluster).get(vec_id) else {
+                continue;
+            };
+            let (dt, offset) = match tables.entry(*cluster) {
+                Entry::Occupied(table) => table.into_mut(),
+                Entry::Vacant(slot) => slot.insert(ividx.list_distance_table(qv, &codebook[*cluster as usize], pq_codebook)?)
+            };
+            if let Ok(distance) = NotNan::new(*offset + adc_distance(dt, entry.get_code())) {
+                max_heap
+                    .push(HeapNode::new(distance, *vec_id, *cluster))
+                    .expect("Error while pushing distance to maxheap");
+            }
+        }
+        distance_results.push(adc_hits(max_heap, ividx.params().metric()));
+    }
+    Ok(distance_results)
+}
+
+/// k nearest entries to the one stored under vec_id in cluster, comparing its code with the other codes
+/// through the SDC tables, for when its raw vector is gone. the lists probed are the ones nearest to
+/// what its code decodes to, the entry itself never shows up
+pub fn search_by_code<F>(ividx: &InvertedIndex, (vec_id, cluster): (u32, Clusters), sdc: &SdcTables, k: usize, params: &SearchParams, mut accept: F) -> KathleenResult<Vec<SearchHit>>
+    where F: FnMut(u32) -> KathleenResult<bool> {
+    let code = ividx.get_cluster(cluster).get(&vec_id)
+        .ok_or_else(|| KathleenError::NotFound(format!("vector {vec_id} in cluster {cluster}")))?
+        .get_code();
+    validate_search(ividx, &[], k, params)?;
+
+    let metric = ividx.params().metric();
+    let mut max_heap: BinaryHeapWrapper<HeapNode> = BinaryHeapWrapper::new(k);
+    for list in sdc.nearest_lists(cluster, code, params.nprobe, metric) {
+        let (dt, offset) = sdc.list_table(cluster, code, list, metric);
+        for (id, entry) in ividx.get_cluster(list).iter() {
+            if *id == vec_id || !accept(*id)? {
+                continue;
+            }
+            if let Ok(distance) = NotNan::new(offset + adc_distance(&dt, entry.get_code())) {
+                max_heap
+                    .push(HeapNode::new(distance, *id, list))
+                    .expect("Error while pushing distance to maxheap");
+            }
+        }
+    }
+    Ok(adc_hits(max_heap, metric))
+}
+
+/// hits of the heap nearest first, their table distances turned into the ones the metric reports.
+/// the conversion keeps the order, so it's only paid for the hits that made it
+fn adc_hits(max_heap: BinaryHeapWrapper<HeapNode>, metric: Metric) -> Vec<SearchHit> {
+    max_heap.sorted()
+        .into_iter()
+        .map(SearchHit::from)
+        .map(|hit| SearchHit { distance: metric.reported(hit.distance), ..hit })
+        .collect()
+}
+
+/// approximate vector a pq code stands for in the list of cluster: its centroid plus the sub-centroids
+/// the code picks. cosine indexes get (roughly) the unit vector back, that's what they encoded
+pub fn decode(code: &PqCode, cluster: Clusters, codebook: &Codebook, pq_codebook: &PqCodebook) -> KathleenResult<Embedding> {
+    let centroid = codebook.get(cluster as usize)
+        .ok_or_else(|| KathleenError::NotFound(format!("centroid of cluster {cluster}")))?;
+    let residual = pq_codebook.reconstruct(code)?;
+    if residual.dim() != centroid.dim() {
+        return Err(KathleenError::DimensionMismatch { expected: centroid.dim(), got: residual.dim() });
+    }
+    Ok(Embedding::new(
+        centroid.into_segments()
+            .zip(residual.into_segments())
+            .map(|(c, r)| Segment::new(c.to_vec().into_iter().zip(r.to_vec()).map(|(c, r)| c + r).collect()))
+            .collect()
+    ))
+}
+
+/// re-ranks the PQ candidates of a single query by their exact distance under metric to their raw vectors
+/// and keeps the k nearest, candidates without a raw vector keep their approximate distance
+pub fn refine<F>(query_vector: &Embedding, candidates: Vec<SearchHit>, k: usize, metric: Metric, mut raw_vector: F) -> KathleenResult<Vec<SearchHit>>
+    where F: FnMut(&SearchHit) -> KathleenResult<Option<Embedding>> {
+    let qv = Array1::from(query_vector.to_vec());
+    let mut max_heap: BinaryHeapWrapper<HeapNode> = BinaryHeapWrapper::new(k);
+    for hit in candidates {
+        let distance = match raw_vector(&hit)? {
+            Some(raw) if raw.dim() != query_vector.dim() => {
+                return Err(KathleenError::DimensionMismatch { expected: query_vector.dim(), got: raw.dim() });
+            },
+            Some(raw) => metric.distance(Array1::from(raw.to_vec()).view(), qv.view()),
+            None => hit.distance
+        };
+        if let Ok(distance) = NotNan::new(distance) {
+            max_heap
+                .push(HeapNode::new(distance, hit.id, hit.cluster))
+                .map_err(|_| KathleenError::InvalidParams("k must be greater than zero".to_string()))?;
+        }
+    }
+    Ok(max_heap.sorted().into_iter().map(SearchHit::from).collect())
+}
+
 #[cfg(test)]
 mod tests {
+    use linfa::prelude::Predict;
     use linfa_nn::distance::{L2Dist, Distance};
     use ndarray::Array1;
 
     use crate::ivfpq::{
-        primitive_types::{DistanceTable, Codebook, Embedding, Segment, IVListEntry}, 
-        ivfpq::{CQ_K_CENTROIDS, EMBEDDING_M_SEGMENTS, AvlWrapper, SEGMENT_DIM},
-        db_api::DatabaseWrapper};
+        primitive_types::{DistanceTable, Embedding, Segment},
+        db_api::DatabaseWrapper,
+        test_utils::{pq_codebook_from, read_embeddings, repo_embedding, test_params, training_embeddings}};
 
     use super::*;
     use std::path::Path;
 
+    /// sequential ids for the embeddings of a test
+    fn ids_for(embs: &[Embedding]) -> Vec<u32> {
+        (0..embs.len() as u32).collect()
+    }
+
+    #[test]
+    fn index_params_get_validated() {
+        assert!(IndexParams::new(12, 5, 8, 8).is_err());
+        assert!(IndexParams::new(12, 4, 257, 8).is_err());
+        assert!(IndexParams::new(12, 4, 8, 0).is_err());
+        let params = IndexParams::new(384, 48, 256, 1024).unwrap();
+        assert_eq!(params.segment_dim(), 8);
+
+        // stored params go through the same checks
+        let stored = serde_cbor::to_vec(&params.with_metric(Metric::Cosine)).unwrap();
+        assert_eq!(serde_cbor::from_slice::<IndexParams>(&stored).unwrap(), params.with_metric(Metric::Cosine));
+        for (dim, m) in [(12, 0), (12, 5)] {
+            let stored = serde_cbor::to_vec(&RawIndexParams { dim, m, ks: 8, nlist: 8, metric: Metric::L2 }).unwrap();
+            assert!(serde_cbor::from_slice::<IndexParams>(&stored).is_err());
+        }
+    }
+
     #[test]
     fn it_searches() {
        // in real world scenario, the way to create an IVF will be by calling the load_ivf method from the db_api
-       let database = DatabaseWrapper::open(Path::new("./dbre")).expect("Opening failed: ");
+       let params = test_params();
+       let database = DatabaseWrapper::open(Path::new("./dbre"), &params).expect("Opening failed: ");
        let mut ividx = database.load_ivf().unwrap();
        let mut model = Model::new();
-       let mut embs_list = vec![];
-       let test_embs_str = std::fs::read_to_string("tests/k_means_test_embs").unwrap();
-        let mut test_embs = test_embs_str.split('\n').into_iter();
-        for _ in 0..EMBEDDINGS_PER_CLUSTER*CENTROIDS_PER_SUBSPACE_CLUSTER {
-            embs_list.push(Embedding::read_from_str(test_embs.next().unwrap()));
-        }
-       let codebook = model.k_means(&mut ividx, &embs_list);
-       let query_vectors = std::fs::read_to_string("./tests/search_query_vectors").unwrap();
-       let mut groomed_qv = query_vectors.split('\n').into_iter();
-       let to_search_embs = [
-           Embedding::read_from_str(groomed_qv.next().unwrap()),
-           Embedding::read_from_str(groomed_qv.next().unwrap())
-       ];
-
-       let results = search(&ividx, &to_search_embs, &codebook, &model);
+       let embs_list = training_embeddings(&params);
+       assert_eq!(embs_list.len(), EMBEDDINGS_PER_CLUSTER*params.nlist());
+       let (codebook, pq_codebook) = model.k_means(&mut ividx, &embs_list, &ids_for(&embs_list)).unwrap();
+       let to_search_embs = read_embeddings("./tests/search_query_vectors", &params);
+
+       let results = search(&ividx, &to_search_embs, &codebook, &pq_codebook, 10, &SearchParams::default());
        println!("{:?}", results);
 
     }
     
+    #[test]
+    fn inserted_embedding_is_its_own_nearest_neighbour() {
+       let params = test_params();
+       let mut ividx = InvertedIndex::empty(params);
+       let mut model = Model::new();
+       let embs_list = training_embeddings(&params);
+       let (codebook, pq_codebook) = model.k_means(&mut ividx, &embs_list, &ids_for(&embs_list)).unwrap();
+       let new_emb = repo_embedding(&params);
+       let cluster = model.predict(&new_emb).unwrap();
+       let vec_id = 1000;
+       ividx.add_embedding_to_cluster(cluster, vec_id, &new_emb, &codebook, &pq_codebook).unwrap();
+
+       let results = search(&ividx, &[new_emb], &codebook, &pq_codebook, 10, &SearchParams::default()).unwrap();
+       assert_eq!(results[0][0].id, vec_id);
+       assert_eq!(results[0][0].cluster, cluster);
+    }
+
+    #[test]
+    fn probing_finds_neighbours_across_cluster_boundaries() {
+       let params = test_params();
+       let mut ividx = InvertedIndex::empty(params);
+       let mut model = Model::new();
+       let embs_list = training_embeddings(&params);
+       let (codebook, pq_codebook) = model.k_means(&mut ividx, &embs_list, &ids_for(&embs_list)).unwrap();
+       let new_emb = Embedding::read_from_str("[34.8, 35.1, 35.3, 35., 34.9, 35.2, 35.1, 35., 34.7, 35.3, 35., 35.1]", &params).unwrap();
+       // store it on the other side of the boundary, in the second nearest list
+       let second_nearest = ividx.get_nearest_centroids(&new_emb, &codebook, 2)[1].0.0;
+       let vec_id = 1000;
+       ividx.add_embedding_to_cluster(second_nearest, vec_id, &new_emb, &codebook, &pq_codebook).unwrap();
+
+       let single_probe = search(&ividx, &[new_emb.clone()], &codebook, &pq_codebook, 10, &SearchParams { nprobe: 1, ..SearchParams::default() }).unwrap();
+       assert!(single_probe[0].iter().all(|hit| hit.id != vec_id));
+       let multi_probe = search(&ividx, &[new_emb], &codebook, &pq_codebook, 10, &SearchParams { nprobe: 2, ..SearchParams::default() }).unwrap();
+       assert!(multi_probe[0].iter().any(|hit| hit.id == vec_id && hit.cluster == second_nearest));
+       assert!(multi_probe[0].windows(2).all(|w| w[0].distance <= w[1].distance));
+    }
+
+    #[test]
+    fn search_returns_k_hits() {
+       let params = test_params();
+       let mut ividx = InvertedIndex::empty(params);
+       let mut model = Model::new();
+       let embs_list = training_embeddings(&params);
+       let (codebook, pq_codebook) = model.k_means(&mut ividx, &embs_list, &ids_for(&embs_list)).unwrap();
+       let all_lists = SearchParams { nprobe: params.nlist(), ..SearchParams::default() };
+       for k in [1, 5, 20, 50] {
+           let results = search(&ividx, &embs_list[..1], &codebook, &pq_codebook, k, &all_lists).unwrap();
+           assert_eq!(results[0].len(), k.min(embs_list.len()));
+       }
+       assert!(search(&ividx, &embs_list[..1], &codebook, &pq_codebook, 0, &all_lists).is_err());
+    }
+
+    #[test]
+    fn refinement_ranks_by_exact_distance() {
+       let params = test_params();
+       let mut ividx = InvertedIndex::empty(params);
+       let mut model = Model::new();
+       let embs_list = training_embeddings(&params);
+       let (codebook, pq_codebook) = model.k_means(&mut ividx, &embs_list, &ids_for(&embs_list)).unwrap();
+       let refined_params = SearchParams { nprobe: params.nlist(), refine_factor: Some(3), ..SearchParams::default() };
+       let qv = &read_embeddings("./tests/search_query_vectors", &params)[0];
+       let exact = |emb: &Embedding| L2Dist::distance(&L2Dist, Array1::from(emb.to_vec()).view(), Array1::from(qv.to_vec()).view());
+       let k = 5;
+
+       let candidates = search(&ividx, std::slice::from_ref(qv), &codebook, &pq_codebook, refined_params.candidates(k), &refined_params).unwrap();
+       assert_eq!(candidates[0].len(), 3 * k);
+       let refined = refine(qv, candidates[0].clone(), k, Metric::L2, |hit| Ok(Some(embs_list[hit.id as usize].clone()))).unwrap();
+       let mut brute_force = embs_list.iter().map(exact).collect::<Vec<f64>>();
+       brute_force.sort_by(|a, b| a.total_cmp(b));
+       assert_eq!(refined.iter().map(|hit| hit.distance).collect::<Vec<f64>>(), brute_force[..k]);
+       assert!(refined.iter().all(|hit| hit.distance == exact(&embs_list[hit.id as usize])));
+
+       // without raw vectors the approximate ranking stays
+       let unrefined = refine(qv, candidates[0].clone(), k, Metric::L2, |_| Ok(None)).unwrap();
+       assert_eq!(unrefined, candidates[0][..k]);
+       let no_refine = SearchParams { refine_factor: Some(0), ..refined_params };
+       assert!(search(&ividx, std::slice::from_ref(qv), &codebook, &pq_codebook, k, &no_refine).is_err());
+    }
+
     // weirdo but works
     #[test]
     fn k_means_works() {
-       let mut ividx = InvertedIndex::empty();
+       let params = test_params();
+       let mut ividx = InvertedIndex::empty(params);
        let mut model = Model::new();
-       let mut embs_list = vec![];
-       let test_embs_str = std::fs::read_to_string("tests/k_means_test_embs").unwrap();
-        let mut test_embs = test_embs_str.split('\n').into_iter();
-        for _ in 0..EMBEDDINGS_PER_CLUSTER*CENTROIDS_PER_SUBSPACE_CLUSTER {
-            embs_list.push(Embedding::read_from_str(test_embs.next().unwrap()));
-        }
-       let codebook = model.k_means(&mut ividx, &embs_list);
+       let embs_list = training_embeddings(&params);
+       let (codebook, pq_codebook) = model.k_means(&mut ividx, &embs_list, &ids_for(&embs_list)).unwrap();
+       assert_eq!(codebook.len(), params.nlist());
+       // one subspace quantizer per segment, each holding ks sub-centroids
+       assert_eq!(pq_codebook.m(), params.m());
+       assert_eq!(pq_codebook.ks(), params.ks());
+       assert!((0..params.m()).all(|j| pq_codebook.subspace(j).iter().all(|c| c.to_vec().len() == params.segment_dim())));
+       assert_eq!(ividx.iter().map(|avl| avl.len()).sum::<usize>(), embs_list.len());
        // check that all codebook embs are found in their respective ividx entry
        for (cluster_no, centroid) in codebook.iter().enumerate() {
            //let found_centroid = ividx
-           //    .get_cluster(cluster_no as u8)
+           //    .get_cluster(cluster_no as Clusters)
            //    .iter()
            //    .filter(|emb| emb.1.get_code().clone() == centroid.encode(&codebook))
            //    .collect::<Vec<_>>();
            //
            //assert_eq!(found_centroid.len(), 1);
            println!("------Cluster {cluster_no}-----");
-           println!("Centroid code: {:?}", centroid.encode(&codebook));
-           println!("Ividx embs: {:?}", ividx.get_cluster(cluster_no as u8));
+           println!("Centroid code: {:?}", centroid.encode(&pq_codebook));
+           println!("Ividx embs: {:?}", ividx.get_cluster(cluster_no as Clusters));
        }
        // list all the embeddings and check there is no one left from the embs_list
     }
 
+    /// ADC has to give the exact distance between the query and what the code decodes to,
+    /// for L2 that only holds if the tables add squared distances up
+    #[test]
+    fn adc_follows_the_metric() {
+       for metric in [Metric::L2, Metric::SquaredL2, Metric::InnerProduct, Metric::Cosine] {
+           let params = test_params().with_metric(metric);
+           let mut ividx = InvertedIndex::empty(params);
+           let mut model = Model::new();
+           let embs_list = training_embeddings(&params);
+           let (codebook, pq_codebook) = model.k_means(&mut ividx, &embs_list, &ids_for(&embs_list)).unwrap();
+           let qv = &read_embeddings("./tests/search_query_vectors", &params)[0];
+           let search_params = SearchParams { nprobe: params.nlist(), ..SearchParams::default() };
+           let hits = search(&ividx, std::slice::from_ref(qv), &codebook, &pq_codebook, 10, &search_params).unwrap();
+           assert_eq!(hits[0].len(), 10);
+           for hit in &hits[0] {
+               let code = ividx.get_cluster(hit.cluster)[&hit.id].get_code();
+               let decoded = Array1::from(decode(code, hit.cluster, &codebook, &pq_codebook).unwrap().to_vec());
+               let expected = match metric {
+                   // entries were stored as unit vectors, so the dot product is what's left of cosine
+                   Metric::Cosine => 1. + Metric::InnerProduct.distance(Array1::from(metric.prepare(qv).to_vec()).view(), decoded.view()),
+                   _ => metric.distance(Array1::from(qv.to_vec()).view(), decoded.view())
+               };
+               assert!((hit.distance - expected).abs() < 1e-9, "{metric:?}: {} vs {expected}", hit.distance);
+           }
+           assert!(hits[0].windows(2).all(|pair| pair[0].distance <= pair[1].distance));
+           assert_eq!(model.predict(qv).unwrap(), ividx.get_nearest_centroids(qv, &codebook, 1)[0].0.0);
+       }
+    }
+
+    #[test]
+    fn codes_decode_to_centroid_plus_sub_centroids() {
+       let params = IndexParams::new(4, 2, 2, 2).unwrap();
+       let emb = |values: Vec<f64>| Embedding::from_base(Array1::from(values), &params).unwrap();
+       let codebook = vec![emb(vec![0., 0., 0., 0.]), emb(vec![10., 10., 10., 10.])];
+       let pq_codebook = PqCodebook::new(vec![
+           vec![Segment::new(vec![1., 2.]), Segment::new(vec![-1., -2.])],
+           vec![Segment::new(vec![3., 4.]), Segment::new(vec![-3., -4.])]
+       ]);
+       assert_eq!(decode(&vec![1, 0], 1, &codebook, &pq_codebook).unwrap(), emb(vec![9., 8., 13., 14.]));
+       // encoding what was decoded gives the same code back
+       let decoded = decode(&vec![0, 1], 0, &codebook, &pq_codebook).unwrap();
+       assert_eq!(decoded.residual(&codebook[0]).encode(&pq_codebook), vec![0, 1]);
+
+       assert!(decode(&vec![0, 2], 0, &codebook, &pq_codebook).is_err());
+       assert!(decode(&vec![0], 0, &codebook, &pq_codebook).is_err());
+       assert!(matches!(decode(&vec![0, 0], 2, &codebook, &pq_codebook), Err(KathleenError::NotFound(_))));
+    }
+
+    #[test]
+    fn rebuilt_model_predicts_like_the_fitted_one() {
+       let params = test_params();
+       let mut ividx = InvertedIndex::empty(params);
+       let mut model = Model::new();
+       let embs_list = training_embeddings(&params);
+       let (codebook, _) = model.k_means(&mut ividx, &embs_list, &ids_for(&embs_list)).unwrap();
+       let rebuilt = Model::from_codebook(codebook, params.metric());
+       assert!(rebuilt.is_trained());
+       for emb in embs_list.iter().chain(&read_embeddings("./tests/search_query_vectors", &params)) {
+           let fitted = model.model.as_ref().unwrap().predict(&DatasetBase::from(Array1::from(emb.to_vec())));
+           assert_eq!(rebuilt.predict(emb).unwrap(), fitted as Clusters);
+       }
+    }
+
+    #[test]
+    fn k_means_rejects_mismatched_embeddings() {
+       let mut ividx = InvertedIndex::empty(test_params());
+       let mut model = Model::new();
+       let other = IndexParams::new(16, 4, 8, 8).unwrap();
+       let embs_list = vec![Embedding::zeros(&other); 8];
+       assert!(matches!(
+           model.k_means(&mut ividx, &embs_list, &ids_for(&embs_list)),
+           Err(KathleenError::DimensionMismatch { expected: 12, got: 16 })
+       ));
+       assert!(matches!(model.predict(&embs_list[0]), Err(KathleenError::NotTrained)));
+    }
+
     mod inverted_index {
 
-        use crate::ivfpq::ivfpq::next_id;
         use crate::ivfpq::primitive_types::PqCode;
         use super::*;
 
         #[test]
         fn adding_embeddings_to_cluster() {
             let embs_per_cluster = 3;
+            let params = test_params();
 
             // declare pre-trained codebook
             // 2 of which are taken from insertion embeddings
-            let mut cb: Codebook = [Embedding::default(); CQ_K_CENTROIDS];
-            let codebook_embs_file = std::fs::read_to_string("tests/codebook_test_embeddings").unwrap();
-            let mut codebook_embs_file = codebook_embs_file.split('\n').into_iter();
-            for element in 0..CQ_K_CENTROIDS {
-                cb[element] = Embedding::read_from_str(codebook_embs_file.next().unwrap());
-            }
-            let mut ividx = InvertedIndex::empty();
+            let cb = pq_codebook_from(&read_embeddings("tests/codebook_test_embeddings", &params));
+            let mut ividx = InvertedIndex::empty(params);
             let wrap1 = ividx.get_cluster_mut(1);
             let test_embs = std::fs::read_to_string("tests/test_embeddings").unwrap();
             let test_embs = test_embs.split('\n').into_iter();
-            let embs_wrap1  = test_embs.take(embs_per_cluster).map(|emb| Embedding::read_from_str(emb));
-            embs_wrap1.for_each(|emb| wrap1.add_embedding(&emb, 1, next_id(), &cb));
+            let embs_wrap1  = test_embs.take(embs_per_cluster).map(|emb| Embedding::read_from_str(emb, &params).unwrap());
+            // zero centroid, so the stored codes are the ones of the raw embeddings
+            let centroid = Embedding::zeros(&params);
+            embs_wrap1.enumerate().for_each(|(vec_id, emb)| wrap1.add_embedding(&emb, &centroid, 1, vec_id as u32, &cb));
             // assert embeddings in both clusters match the specified in txt file
             let embeddings_clust_1 = ividx.get_cluster(1).get_all()
                 .iter().map(|v| v.get_code().clone()).collect::<Vec<PqCode>>();
             assert_eq!(
-                vec![[1_u8, 3_u8, 3_u8, 3_u8], [0_u8, 3_u8, 3_u8, 3_u8], [1_u8, 3_u8, 0_u8, 3_u8]], embeddings_clust_1
+                vec![vec![1_u8, 3_u8, 3_u8, 3_u8], vec![0_u8, 3_u8, 3_u8, 3_u8], vec![1_u8, 3_u8, 0_u8, 3_u8]], embeddings_clust_1
                 );
 
         }
 
         #[test]
         fn distance_table_gets_computed() {
+            let params = test_params();
             // create codebook
-            let mut codebook: Codebook = [Embedding::default(); CQ_K_CENTROIDS];
-            let codebook_embs_file = std::fs::read_to_string("tests/codebook_test_embeddings").unwrap();
-            let mut codebook_embs_file = codebook_embs_file.split('\n').into_iter();
-            for element in 0..CQ_K_CENTROIDS {
-                codebook[element] = Embedding::read_from_str(codebook_embs_file.next().unwrap());
-            }
+            let codebook = pq_codebook_from(&read_embeddings("tests/codebook_test_embeddings", &params));
             // create query_vector (in real scenarios should be the residual)
-            let query_vector: Embedding = Embedding::read_from_str(
-                std::fs::read_to_string("./tests/query_vectors").unwrap().split('\n').into_iter().next().unwrap()
-            );
-            let dt: DistanceTable = InvertedIndex::compute_distance_table(&query_vector, &codebook);
+            let query_vector: Embedding = read_embeddings("./tests/query_vectors", &params).remove(0);
+            let dt: DistanceTable = InvertedIndex::compute_distance_table(&query_vector, &codebook, Metric::L2);
             let get_distance = |c_j: Segment, qv: Segment| L2Dist::distance(&L2Dist, Array1::from(c_j.to_vec()).view(), Array1::from(qv.to_vec()).view()) ;
-            let expected_dt: DistanceTable = [
-                [
-                    get_distance(Segment::new([1.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([1.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([1.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([1.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM]))
-                ],
-                [
-                    get_distance(Segment::new([2.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([2.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([2.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([2.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM]))
-                ],
-                [
-                    get_distance(Segment::new([3.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([3.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([3.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([3.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM]))
-                ],
-                [
-                    get_distance(Segment::new([4.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([4.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([4.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([4.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM]))
-                ],
-                [
-                    get_distance(Segment::new([1.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([1.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([1.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([1.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM]))
-                ],
-                [
-                    get_distance(Segment::new([2.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([2.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([2.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([2.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM]))
-                ],
-                [
-                    get_distance(Segment::new([3.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([3.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([3.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([3.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM]))
-                ],
-                [
-                    get_distance(Segment::new([4.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([4.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([4.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM])),
-                    get_distance(Segment::new([4.0; SEGMENT_DIM]), Segment::new([1.0; SEGMENT_DIM]))
-                ]
-            ];
+            let seg = |v: f64| Segment::new(vec![v; params.segment_dim()]);
+            let expected_dt: DistanceTable = [1.0, 2.0, 3.0, 4.0, 1.0, 2.0, 3.0, 4.0]
+                .into_iter()
+                .map(|c| vec![get_distance(seg(c), seg(1.0)); params.m()])
+                .collect();
             assert_eq!(dt, expected_dt);
         }
     }
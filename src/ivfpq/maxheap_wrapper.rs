@@ -1,44 +1,65 @@
-use heapless::binary_heap::{BinaryHeap, Max};
+use std::collections::BinaryHeap;
 use derivative::{self, Derivative};
 use ordered_float::NotNan;
-use super::primitive_types::PqCode;
+use super::primitive_types::{Clusters, SearchHit};
 
+/// candidate kept in the heap while scanning the inverted lists, ordered by distance only
 #[derive(Derivative, Clone)]
 #[derivative(PartialOrd, Ord, PartialEq, Eq, Debug)]
-pub struct HeapNode<'a> {
+pub struct HeapNode {
     distance: NotNan<f64>,
     #[derivative(Ord="ignore")]
     #[derivative(PartialOrd="ignore")]
     #[derivative(PartialEq="ignore")]
-    code: &'a PqCode
+    id: u32,
+    #[derivative(Ord="ignore")]
+    #[derivative(PartialOrd="ignore")]
+    #[derivative(PartialEq="ignore")]
+    cluster: Clusters
 }
 
-impl<'a> HeapNode<'a> {
-    pub fn new(d: NotNan<f64>, c: &'a PqCode) -> Self {
-        Self { distance: d, code: c }
+impl HeapNode {
+    pub fn new(d: NotNan<f64>, id: u32, cluster: Clusters) -> Self {
+        Self { distance: d, id, cluster }
+    }
+}
+
+impl From<HeapNode> for SearchHit {
+    fn from(node: HeapNode) -> Self {
+        SearchHit {
+            id: node.id,
+            distance: node.distance.into_inner(),
+            cluster: node.cluster,
+            key: None,
+            metadata: None
+        }
     }
 }
 
 
-pub(crate) struct BinaryHeapWrapper<T, const N:usize>(BinaryHeap<T, Max, N>);
+/// bounded max-heap keeping the `capacity` smallest items pushed into it (top-k collector)
+pub(crate) struct BinaryHeapWrapper<T>(BinaryHeap<T>, usize);
 
-impl<T: Ord + PartialOrd + Clone, const N:usize> BinaryHeapWrapper<T, N> {
-    pub fn new() -> Self {
-        Self(BinaryHeap::new())
+impl<T: Ord + PartialOrd + Clone> BinaryHeapWrapper<T> {
+    /// nothing gets allocated up front, capacity is only a bound and may be far above what is ever pushed
+    pub fn new(capacity: usize) -> Self {
+        Self(BinaryHeap::new(), capacity)
     }
 
     pub fn push(&mut self, item: T) -> Result<(), T> {
-        if self.0.len() == self.0.capacity() {
+        if self.1 == 0 {
+            return Err(item);
+        }
+        if self.0.len() == self.1 {
             let peek = self.0.peek().unwrap();
             if peek > &item {
                 self.0.pop();
-                return self.0.push(item);
-            } else {
-                Ok(())
+                self.0.push(item);
             }
         } else {
-            return self.0.push(item);
+            self.0.push(item);
         }
+        Ok(())
     } 
     pub fn sorted(self) -> Vec<T> {
         let mut vec = self.0.into_vec();
@@ -51,13 +72,11 @@ impl<T: Ord + PartialOrd + Clone, const N:usize> BinaryHeapWrapper<T, N> {
 
 #[cfg(test)]
 mod tests {
-    use crate::ivfpq::ivfpq::EMBEDDING_M_SEGMENTS;
-
     use super::*;
 
     #[test]
     fn default_behaviour_works() {
-        let mut heap: BinaryHeapWrapper<usize, 4> = BinaryHeapWrapper::new();
+        let mut heap: BinaryHeapWrapper<usize> = BinaryHeapWrapper::new(4);
 
         // We can use peek to look at the next item in the heap. In this case,
         // there's no items in there yet so we get None.
@@ -93,9 +112,17 @@ mod tests {
         assert!(heap.0.is_empty())
     }
 
+    #[test]
+    fn huge_capacities_cost_nothing_up_front() {
+        let mut heap: BinaryHeapWrapper<usize> = BinaryHeapWrapper::new(usize::MAX);
+        heap.push(3).unwrap();
+        heap.push(1).unwrap();
+        assert_eq!(heap.sorted(), vec![1, 3]);
+    }
+
     #[test]
     fn expected_behaviour_works() {
-        let mut heap: BinaryHeapWrapper<usize, 4> = BinaryHeapWrapper::new();
+        let mut heap: BinaryHeapWrapper<usize> = BinaryHeapWrapper::new(4);
 
         // We can use peek to look at the next item in the heap. In this case,
         // there's no items in there yet so we get None.
@@ -135,9 +162,16 @@ mod tests {
 
     }
 
+    #[test]
+    fn zero_capacity_rejects_items() {
+        let mut heap: BinaryHeapWrapper<usize> = BinaryHeapWrapper::new(0);
+        assert_eq!(heap.push(1), Err(1));
+        assert!(heap.sorted().is_empty());
+    }
+
     #[test]
     fn expected_behaviour_works_with_heap_nodes() {
-        let mut heap: BinaryHeapWrapper<HeapNode<'_>, 4> = BinaryHeapWrapper::new();
+        let mut heap: BinaryHeapWrapper<HeapNode> = BinaryHeapWrapper::new(4);
 
         // We can use peek to look at the next item in the heap. In this case,
         // there's no items in there yet so we get None.
@@ -146,29 +180,35 @@ mod tests {
         // Let's add some scores...
         heap.push(HeapNode{
             distance: NotNan::new(25.333).unwrap(),
-            code: &[1; EMBEDDING_M_SEGMENTS]
+            id: 1,
+            cluster: 0
         }).unwrap();
         heap.push(HeapNode{
             distance: NotNan::new(12.4).unwrap(),
-            code: &[1; EMBEDDING_M_SEGMENTS]
+            id: 1,
+            cluster: 0
         }).unwrap();
         heap.push(HeapNode{
             distance: NotNan::new(1.6).unwrap(),
-            code: &[1; EMBEDDING_M_SEGMENTS]
+            id: 1,
+            cluster: 0
         }).unwrap();
         heap.push(HeapNode{
             distance: NotNan::new(13.16).unwrap(),
-            code: &[1; EMBEDDING_M_SEGMENTS]
+            id: 1,
+            cluster: 0
         }).unwrap();
         heap.push(HeapNode{
             distance: NotNan::new(22.43).unwrap(),
-            code: &[1; EMBEDDING_M_SEGMENTS]
+            id: 1,
+            cluster: 0
         }).unwrap();
 
         // Now peek shows the most important item in the heap.
         assert_eq!(heap.0.peek(), Some(&HeapNode{
             distance: NotNan::new(22.43).unwrap(),
-            code: &[1; EMBEDDING_M_SEGMENTS]
+            id: 1,
+            cluster: 0
         }));
 
         // We can check the length of a heap.
@@ -183,19 +223,23 @@ mod tests {
         // If we instead pop these scores, they should come back in order.
         assert_eq!(heap.0.pop(), Some(HeapNode{
             distance: NotNan::new(22.43).unwrap(),
-            code: &[1; EMBEDDING_M_SEGMENTS]
+            id: 1,
+            cluster: 0
         }));
         assert_eq!(heap.0.pop(), Some(HeapNode{
             distance: NotNan::new(13.16).unwrap(),
-            code: &[1; EMBEDDING_M_SEGMENTS]
+            id: 1,
+            cluster: 0
         }));
         assert_eq!(heap.0.pop(), Some(HeapNode{
             distance: NotNan::new(12.4).unwrap(),
-            code: &[1; EMBEDDING_M_SEGMENTS]
+            id: 1,
+            cluster: 0
         }));
         assert_eq!(heap.0.pop(), Some(HeapNode{
             distance: NotNan::new(1.6).unwrap(),
-            code: &[1; EMBEDDING_M_SEGMENTS]
+            id: 1,
+            cluster: 0
         }));
         assert_eq!(heap.0.pop(), None);
 
@@ -0,0 +1,128 @@
+use ndarray::{Array1, ArrayView1};
+use linfa_nn::distance::{L2Dist, Distance};
+use serde::{Serialize, Deserialize};
+use super::primitive_types::{Embedding, Segment};
+
+// METRICS
+// every metric is reported as a distance, smaller meaning more similar:
+//     L2           ||q - x||
+//     SquaredL2    ||q - x||^2
+//     InnerProduct -<q, x>
+//     Cosine       1 - <q, x> / (||q|| ||x||)
+// the L2 family gets its PQ tables from the residual of the query, the inner product family
+// from the query itself plus a per-list term, see InvertedIndex::list_distance_table.
+// L2 tables hold squared distances so that they add up over the segments, the square root
+// is only taken on the hits that get reported.
+// cosine indexes store and search unit vectors, so cosine boils down to an inner product
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Metric {
+    #[default]
+    L2,
+    SquaredL2,
+    InnerProduct,
+    Cosine
+}
+
+impl Metric {
+    pub fn distance(&self, a: ArrayView1<f64>, b: ArrayView1<f64>) -> f64 {
+        match self {
+            Metric::L2 => L2Dist.distance(a, b),
+            Metric::SquaredL2 => L2Dist.rdistance(a, b),
+            Metric::InnerProduct => -a.dot(&b),
+            Metric::Cosine => {
+                let norms = a.dot(&a).sqrt() * b.dot(&b).sqrt();
+                // zero vectors are as far from everything as orthogonal ones
+                if norms == 0. {
+                    return 1.;
+                }
+                1. - a.dot(&b) / norms
+            }
+        }
+    }
+
+    pub fn embedding_distance(&self, a: &Embedding, b: &Embedding) -> f64 {
+        self.distance(Array1::from(a.to_vec()).view(), Array1::from(b.to_vec()).view())
+    }
+
+    pub fn segment_distance(&self, a: &Segment, b: &Segment) -> f64 {
+        self.distance(Array1::from(a.to_vec()).view(), Array1::from(b.to_vec()).view())
+    }
+
+    /// what the per-segment entries of the PQ tables hold, their sum has to mean something
+    pub fn table_metric(&self) -> Metric {
+        match self {
+            Metric::L2 => Metric::SquaredL2,
+            metric => *metric
+        }
+    }
+
+    /// turns a sum of table entries into the distance the metric reports
+    pub fn reported(&self, adc: f64) -> f64 {
+        match self {
+            // rounding may leave a tiny negative sum behind
+            Metric::L2 => adc.max(0.).sqrt(),
+            _ => adc
+        }
+    }
+
+    /// whether PQ tables are built from inner products with the query rather than from its residual
+    pub fn is_inner_product(&self) -> bool {
+        matches!(self, Metric::InnerProduct | Metric::Cosine)
+    }
+
+    /// the vector as the quantizers get to see it: unit length for cosine, untouched otherwise
+    pub fn prepare(&self, emb: &Embedding) -> Embedding {
+        if *self != Metric::Cosine {
+            return emb.clone();
+        }
+        let norm = emb.to_vec().iter().map(|v| v * v).sum::<f64>().sqrt();
+        if norm == 0. {
+            return emb.clone();
+        }
+        Embedding::new(
+            emb.into_segments()
+                .map(|seg| Segment::new(seg.to_vec().into_iter().map(|v| v / norm).collect()))
+                .collect()
+        )
+    }
+
+    /// turns a distance back into the score the metric is usually quoted in:
+    /// the inner product or the cosine similarity, L2 distances stay as they are
+    pub fn score(&self, distance: f64) -> f64 {
+        match self {
+            Metric::L2 | Metric::SquaredL2 => distance,
+            Metric::InnerProduct => -distance,
+            Metric::Cosine => 1. - distance
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ivfpq::ivfpq::IndexParams;
+
+    #[test]
+    fn metrics_report_distances() {
+        let params = IndexParams::new(2, 1, 2, 1).unwrap();
+        let a = Embedding::from_base(Array1::from(vec![3., 0.]), &params).unwrap();
+        let b = Embedding::from_base(Array1::from(vec![0., 4.]), &params).unwrap();
+        let c = Embedding::from_base(Array1::from(vec![6., 0.]), &params).unwrap();
+        assert_eq!(Metric::L2.embedding_distance(&a, &b), 5.);
+        assert_eq!(Metric::SquaredL2.embedding_distance(&a, &b), 25.);
+        assert_eq!(Metric::InnerProduct.embedding_distance(&a, &c), -18.);
+        assert_eq!(Metric::Cosine.embedding_distance(&a, &b), 1.);
+        assert_eq!(Metric::Cosine.embedding_distance(&a, &c), 0.);
+        assert_eq!(Metric::Cosine.embedding_distance(&a, &Embedding::zeros(&params)), 1.);
+        assert_eq!(Metric::Cosine.score(0.25), 0.75);
+        assert_eq!(Metric::InnerProduct.score(-18.), 18.);
+        assert_eq!(Metric::L2.table_metric(), Metric::SquaredL2);
+        assert_eq!(Metric::L2.reported(25.), 5.);
+        assert_eq!(Metric::SquaredL2.reported(25.), 25.);
+
+        assert_eq!(Metric::Cosine.prepare(&b).to_vec(), vec![0., 1.]);
+        assert_eq!(Metric::InnerProduct.prepare(&b), b);
+        assert_eq!(Metric::Cosine.prepare(&Embedding::zeros(&params)), Embedding::zeros(&params));
+    }
+}
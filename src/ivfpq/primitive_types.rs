@@ -3,108 +3,208 @@ use serde::{Deserialize, Serialize};
 use core::{slice::Iter, f64};
 use std::str::FromStr;
 
-use crate::ivfpq::ivfpq::{SEGMENT_DIM, EMBEDDING_M_SEGMENTS, CQ_K_CENTROIDS, CENTROIDS_PER_SUBSPACE_CLUSTER, InvertedIndex};
+use crate::ivfpq::ivfpq::{IndexParams, InvertedIndex};
+use crate::ivfpq::error::{KathleenError, KathleenResult};
+use crate::ivfpq::metric::Metric;
 
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Default)]
-pub struct Segment([f64; SEGMENT_DIM]);
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Default)]
+pub struct Segment(Vec<f64>);
 
 impl Segment {
-    pub fn new(src: [f64; SEGMENT_DIM]) -> Self {
+    pub fn new(src: Vec<f64>) -> Self {
         Self(src)
     }
 
     pub fn to_vec(&self) -> Vec<f64> {
-        Vec::from(self.0)
+        self.0.clone()
     }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
-pub struct Embedding([Segment; EMBEDDING_M_SEGMENTS]);
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Embedding(Vec<Segment>);
 
 impl Embedding {
     pub fn into_segments<'a>(&'a self) -> Iter<'a, Segment> {
         self.0.iter()
     }
-    pub fn new(src: [Segment; EMBEDDING_M_SEGMENTS]) -> Self {
+    pub fn new(src: Vec<Segment>) -> Self {
         Self(src)
     }
 
-    pub fn from_base(src: Array1<f64>) -> Self {
-        let mut emb = [Segment([0.0; SEGMENT_DIM]); EMBEDDING_M_SEGMENTS];
-        let mut last = 0;
-        for segment in 0..EMBEDDING_M_SEGMENTS {
-            let mut seg = [0.0; SEGMENT_DIM];
-            for element in last..last+SEGMENT_DIM {
-                seg[element-last] = src[element];
-            }
-            last += SEGMENT_DIM;
-            emb[segment] = Segment(seg);
+    /// all-zeros embedding shaped after the index geometry
+    pub fn zeros(params: &IndexParams) -> Self {
+        Self(vec![Segment(vec![0.0; params.segment_dim()]); params.m()])
+    }
+
+    pub fn from_base(src: Array1<f64>, params: &IndexParams) -> KathleenResult<Self> {
+        if src.len() != params.dim() {
+            return Err(KathleenError::DimensionMismatch { expected: params.dim(), got: src.len() });
         }
-        Embedding(emb)
+        let emb = src
+            .to_vec()
+            .chunks(params.segment_dim())
+            .map(|seg| Segment(seg.to_vec()))
+            .collect::<Vec<Segment>>();
+        Ok(Embedding(emb))
     }
 
-    pub fn read_from_str(src: &str) -> Self {
+    pub fn read_from_str(src: &str, params: &IndexParams) -> KathleenResult<Self> {
         let mut string_src = src.to_string();
         string_src = string_src.replace('[', "");
         string_src = string_src.replace(']', "");
-        let mut src_iter = string_src.split(',').into_iter();
-        let mut embedding = [Segment::default(); EMBEDDING_M_SEGMENTS];
-        for seg in 0..EMBEDDING_M_SEGMENTS {
-            let mut segment = [0.0; SEGMENT_DIM];
-            for f in 0..SEGMENT_DIM {
-                let nxt = src_iter.next().unwrap().replace(' ', "");
-                println!("{nxt}");
-                segment[f] = f64::from_str(&nxt).unwrap();
-            }
-            embedding[seg] = Segment::new(segment);
-        }
-        Embedding::new(embedding)
+        let values = string_src
+            .split(',')
+            .map(|nxt| {
+                let nxt = nxt.replace(' ', "");
+                f64::from_str(&nxt).map_err(|e| KathleenError::Parse(format!("invalid embedding value {nxt:?}: {e}")))
+            })
+            .collect::<KathleenResult<Vec<f64>>>()?;
+        Embedding::from_base(Array1::from(values), params)
     }
 
     pub fn to_vec(&self) -> Vec<f64> {
         let mut arr = Vec::new();
-        for segment in self.0 {
+        for segment in &self.0 {
             arr.append(&mut segment.0.to_vec())
         }
         arr
     }
 
-    pub fn encode(&self, cb: &Codebook) -> PqCode {
-        let dt = InvertedIndex::compute_distance_table(&self, cb);
-        let mut mins_array: Vec<(Clusters, f64)> /* (clust_no, min_dist) */= vec![(0, std::f64::MAX); EMBEDDING_M_SEGMENTS];
+    /// segment-wise self - centroid
+    pub fn residual(&self, centroid: &Embedding) -> Embedding {
+        Embedding(
+            self.0.iter()
+                .zip(&centroid.0)
+                .map(|(seg, c_seg)| Segment(seg.0.iter().zip(&c_seg.0).map(|(v, c)| v - c).collect()))
+                .collect()
+        )
+    }
+
+    /// number of raw dimensions held by the embedding
+    pub fn dim(&self) -> usize {
+        self.0.iter().map(|seg| seg.0.len()).sum()
+    }
+
+    /// picks, for every segment, the nearest sub-centroid of that segment's subspace quantizer
+    pub fn encode(&self, pq_cb: &PqCodebook) -> PqCode {
+        // codes minimize the reconstruction error whatever the index metric
+        let dt = InvertedIndex::compute_distance_table(self, pq_cb, Metric::SquaredL2);
+        let mut mins_array: Vec<(CodeWord, f64)> /* (clust_no, min_dist) */= vec![(0, std::f64::MAX); self.0.len()];
         dt.iter()
             .enumerate()
             .for_each(|(clust_no, next_cluster)| next_cluster.iter().enumerate().for_each(|(seg_no, next_seg)| {
                 if &mins_array[seg_no].1 > next_seg  {
-                    mins_array[seg_no] = (clust_no as u8, next_seg.clone());
+                    mins_array[seg_no] = (clust_no as CodeWord, *next_seg);
                 }
             }));
-        let mut code = [0; EMBEDDING_M_SEGMENTS];
-        mins_array.into_iter().enumerate().for_each(|(ind, (clust, _))| code[ind] = clust);
-        code
+        mins_array.into_iter().map(|(clust, _)| clust).collect()
     }
 }
 
-impl Default for Embedding {
-    fn default() -> Self {
-        Self([Default::default(); EMBEDDING_M_SEGMENTS])
+/// Product quantizer: one set of ks sub-centroids for each of the m subspaces,
+/// trained on the residuals left by the coarse quantizer
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PqCodebook(Vec<Vec<Segment>>);
+
+impl PqCodebook {
+    /// takes the sub-centroids as [subspace][centroid]
+    pub fn new(src: Vec<Vec<Segment>>) -> Self {
+        Self(src)
+    }
+
+    pub fn zeros(params: &IndexParams) -> Self {
+        Self(vec![vec![Segment(vec![0.0; params.segment_dim()]); params.ks()]; params.m()])
+    }
+
+    /// sub-centroids of the j-th subspace
+    pub fn subspace(&self, j: usize) -> &[Segment] {
+        &self.0[j]
+    }
+
+    /// number of subspaces (m)
+    pub fn m(&self) -> usize {
+        self.0.len()
+    }
+
+    /// sub-centroids per subspace (ks)
+    pub fn ks(&self) -> usize {
+        self.0.first().map(|sub| sub.len()).unwrap_or(0)
+    }
+
+    /// residual a code stands for: the sub-centroid it picks in every subspace, one after the other
+    pub fn reconstruct(&self, code: &PqCode) -> KathleenResult<Embedding> {
+        if code.len() != self.m() {
+            return Err(KathleenError::InvalidParams(format!("code of {} words for {} subspaces", code.len(), self.m())));
+        }
+        code.iter()
+            .enumerate()
+            .map(|(j, word)| self.0[j].get(*word as usize)
+                .cloned()
+                .ok_or_else(|| KathleenError::InvalidParams(format!("code word {word} out of the {} sub-centroids of subspace {j}", self.ks()))))
+            .collect::<KathleenResult<Vec<Segment>>>()
+            .map(Embedding)
     }
 }
 
-pub(super) type PqCode = [Clusters; EMBEDDING_M_SEGMENTS];
-pub(super) type Clusters = u8;
-pub(super) type DBResult<T> = Result<T, rocksdb::Error>; // may change this error type
-pub(super) type DistanceTable = [[f64; EMBEDDING_M_SEGMENTS]; CENTROIDS_PER_SUBSPACE_CLUSTER];
-pub(super) type Codebook = [Embedding; CQ_K_CENTROIDS];
-
-fn code_from_src(source: &str) -> PqCode {
-    let mut no_spaces = source.replace(' ', "");
-    no_spaces.remove(0);
-    no_spaces.remove(no_spaces.len()-1);
-    let mut code: PqCode = [0; EMBEDDING_M_SEGMENTS];
-    no_spaces.split(',').enumerate().for_each(|(ind, c)| code[ind] = c.parse::<u32>().unwrap() as Clusters );
-    code
+pub(super) type PqCode = Vec<CodeWord>;
+/// index of a centroid inside one subspace quantizer (ks <= 256)
+pub(super) type CodeWord = u8;
+pub(super) type Clusters = u32;
+pub(super) type DBResult<T> = KathleenResult<T>;
+/// ks x m table, indexed as [sub-centroid][segment]
+pub(super) type DistanceTable = Vec<Vec<f64>>;
+/// coarse quantizer centroids, one per inverted list
+pub(super) type Codebook = Vec<Embedding>;
+
+/// owned search result, independent from the index it was retrieved from
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SearchHit {
+    /// vector id, the key of the entry in its AvlWrapper
+    pub id: u32,
+    pub distance: f64,
+    /// inverted list the vector lives in
+    pub cluster: Clusters,
+    /// external key (e.g. "owner/repo") the vector was inserted under, if any
+    pub key: Option<String>,
+    /// document stored along with the vector, if any
+    pub metadata: Option<RepoMetadata>
+}
+
+/// what gets shown about a repository next to its search hit
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RepoMetadata {
+    /// "owner/repo"
+    pub name: String,
+    pub description: Option<String>,
+    /// primary language as reported by GitHub
+    pub language: Option<String>,
+    /// SPDX identifier, documents stored before licenses were tracked have none
+    #[serde(default)]
+    pub license: Option<String>,
+    pub stars: u32,
+    pub topics: Vec<String>
+}
+
+/// a GitHub user as far as searching on their behalf goes
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct UserProfile {
+    pub login: String,
+    /// cluster most of the user's own repos fall in
+    pub home: Clusters,
+    /// number of repos the home cluster was derived from
+    pub repos: u32
+}
+
+fn code_from_src(source: &str) -> KathleenResult<PqCode> {
+    let no_spaces = source.replace(' ', "");
+    let inner = no_spaces
+        .strip_prefix('[')
+        .and_then(|code| code.strip_suffix(']'))
+        .ok_or_else(|| KathleenError::Parse(format!("malformed pq code {source:?}")))?;
+    inner.split(',')
+        .map(|c| c.parse::<CodeWord>().map_err(|e| KathleenError::Parse(format!("invalid code word {c:?}: {e}"))))
+        .collect()
 }
 
 
@@ -125,19 +225,23 @@ impl IVListEntry {
         }
     }
 
-    pub fn from_str(source: &str) -> Self {
-        let splitted = source.split(';').collect::<Vec<&str>>();
-        let code = splitted[0];
-        let cluster = splitted[1];
-        Self { 
-            pq_code: code_from_src(code), 
-            cluster: cluster.parse::<Clusters>().unwrap()
-        }
+    pub fn from_str(source: &str) -> KathleenResult<Self> {
+        let (code, cluster) = source.split_once(';')
+            .ok_or_else(|| KathleenError::Parse(format!("malformed inverted list entry {source:?}")))?;
+        Ok(Self { 
+            pq_code: code_from_src(code)?, 
+            cluster: cluster.trim().parse::<Clusters>()
+                .map_err(|e| KathleenError::Parse(format!("invalid cluster {cluster:?}: {e}")))?
+        })
     }
 
     pub fn get_code(&self) -> &PqCode {
         &self.pq_code
     }
+
+    pub fn get_cluster(&self) -> Clusters {
+        self.cluster
+    }
 }
 
 impl ToString for IVListEntry {
@@ -149,27 +253,48 @@ impl ToString for IVListEntry {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ivfpq::ivfpq::InvertedIndex;
+    use crate::ivfpq::test_utils::{pq_codebook_from, test_params};
+
    #[test]
    fn encoding_works() {
         let embs_per_cluster = 3;
-        let mut cb: Codebook = [Embedding::default(); CQ_K_CENTROIDS];
+        let params = test_params();
         let codebook_embs_file = std::fs::read_to_string("tests/codebook_test_embeddings").unwrap();
-        let mut codebook_embs_file = codebook_embs_file.split('\n').into_iter();
-        for element in 0..CQ_K_CENTROIDS {
-            cb[element] = Embedding::read_from_str(codebook_embs_file.next().unwrap());
-        }
+        let cb: Codebook = codebook_embs_file.lines()
+            .take(params.ks())
+            .map(|emb| Embedding::read_from_str(emb, &params).unwrap())
+            .collect();
+        let pq_cb = pq_codebook_from(&cb);
        let test_embs = std::fs::read_to_string("tests/test_embeddings").unwrap();
        let test_embs = test_embs.split('\n').into_iter();
-       let embs_to_encode  = test_embs.take(embs_per_cluster).map(|emb| Embedding::read_from_str(emb));
+       let embs_to_encode  = test_embs.take(embs_per_cluster).map(|emb| Embedding::read_from_str(emb, &params).unwrap());
        let encoded_embs = embs_to_encode
-           .map(|emb| emb.encode(&cb).clone())
+           .map(|emb| emb.encode(&pq_cb))
            .collect::<Vec<PqCode>>();
        
         assert_eq!(
-            vec![[1_u8, 3_u8, 3_u8, 3_u8], [0_u8, 3_u8, 3_u8, 3_u8], [1_u8, 3_u8, 0_u8, 3_u8]], encoded_embs
+            vec![vec![1_u8, 3_u8, 3_u8, 3_u8], vec![0_u8, 3_u8, 3_u8, 3_u8], vec![1_u8, 3_u8, 0_u8, 3_u8]], encoded_embs
             );
 
    }
-}
 
+   #[test]
+   fn mismatched_dimensions_are_rejected() {
+        let params = test_params();
+        assert!(matches!(
+            Embedding::read_from_str("[1., 2., 3.]", &params),
+            Err(KathleenError::DimensionMismatch { expected: 12, got: 3 })
+        ));
+        assert!(Embedding::from_base(Array1::zeros(16), &params).is_err());
+   }
+
+   #[test]
+   fn malformed_input_is_an_error() {
+        let params = test_params();
+        assert!(matches!(Embedding::read_from_str("[1., 2., x, 4.]", &params), Err(KathleenError::Parse(_))));
+        assert!(matches!(IVListEntry::from_str("[1, 3, 3, 3]"), Err(KathleenError::Parse(_))));
+        assert!(matches!(IVListEntry::from_str("[1, 3, 300, 3];0"), Err(KathleenError::Parse(_))));
+        assert!(matches!(IVListEntry::from_str(" 1, 3;0"), Err(KathleenError::Parse(_))));
+        assert_eq!(IVListEntry::from_str(" [1, 3, 3, 3];0").unwrap(), IVListEntry::new(vec![1, 3, 3, 3], 0));
+   }
+}
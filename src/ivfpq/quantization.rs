@@ -0,0 +1,96 @@
+use super::{metric::Metric, primitive_types::Embedding};
+
+// QUANTIZATION ERROR
+// how much of a vector gets lost when it's stored as centroid + pq code, measured as the squared
+// distance between the vector and what its code decodes to. squared errors add up over the subspaces,
+// so a subspace with a large share of the total is where more sub-centroids (ks) or a finer split (m)
+// would pay off
+
+/// summary of a set of squared reconstruction errors, percentiles by nearest rank
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorStats {
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64
+}
+
+impl ErrorStats {
+    /// all zeros without errors to summarize
+    pub fn of(mut errors: Vec<f64>) -> Self {
+        if errors.is_empty() {
+            return Self::default();
+        }
+        errors.sort_by(f64::total_cmp);
+        let percentile = |p: f64| {
+            let rank = (p / 100. * errors.len() as f64).ceil() as usize;
+            errors[rank.clamp(1, errors.len()) - 1]
+        };
+        Self {
+            mean: errors.iter().sum::<f64>() / errors.len() as f64,
+            p50: percentile(50.),
+            p90: percentile(90.),
+            p99: percentile(99.),
+            max: errors[errors.len() - 1]
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuantizationReport {
+    /// vectors the errors were measured on
+    pub vectors: usize,
+    /// one entry per subspace, in segment order
+    pub subspaces: Vec<ErrorStats>,
+    /// error of whole vectors
+    pub total: ErrorStats
+}
+
+impl QuantizationReport {
+    /// compares every (original, decoded) pair segment by segment, m is the number of subspaces
+    pub fn measure(pairs: &[(Embedding, Embedding)], m: usize) -> Self {
+        let mut per_subspace = vec![Vec::with_capacity(pairs.len()); m];
+        let mut totals = Vec::with_capacity(pairs.len());
+        for (original, decoded) in pairs {
+            let mut total = 0.;
+            for (j, (seg, approx)) in original.into_segments().zip(decoded.into_segments()).enumerate() {
+                let error = Metric::SquaredL2.segment_distance(seg, approx);
+                per_subspace[j].push(error);
+                total += error;
+            }
+            totals.push(total);
+        }
+        Self {
+            vectors: pairs.len(),
+            subspaces: per_subspace.into_iter().map(ErrorStats::of).collect(),
+            total: ErrorStats::of(totals)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array1;
+    use super::*;
+    use crate::ivfpq::ivfpq::IndexParams;
+
+    #[test]
+    fn errors_get_summarized() {
+        let stats = ErrorStats::of((1..=10).rev().map(f64::from).collect());
+        assert_eq!(stats, ErrorStats { mean: 5.5, p50: 5., p90: 9., p99: 10., max: 10. });
+        assert_eq!(ErrorStats::of(vec![]), ErrorStats::default());
+        assert_eq!(ErrorStats::of(vec![2.]).p50, 2.);
+
+        let params = IndexParams::new(4, 2, 2, 1).unwrap();
+        let emb = |values: Vec<f64>| Embedding::from_base(Array1::from(values), &params).unwrap();
+        let report = QuantizationReport::measure(&[
+            (emb(vec![1., 1., 1., 1.]), emb(vec![1., 1., 1., 1.])),
+            (emb(vec![0., 0., 0., 0.]), emb(vec![3., 4., 0., 1.]))
+        ], 2);
+        assert_eq!(report.vectors, 2);
+        assert_eq!(report.subspaces[0], ErrorStats { mean: 12.5, p50: 0., p90: 25., p99: 25., max: 25. });
+        assert_eq!(report.subspaces[1].max, 1.);
+        assert_eq!(report.total.max, 26.);
+    }
+}
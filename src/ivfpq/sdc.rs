@@ -0,0 +1,195 @@
+use ndarray::Array1;
+use serde::{Serialize, Deserialize};
+use super::{
+    metric::Metric,
+    primitive_types::{Clusters, Codebook, DistanceTable, PqCode, PqCodebook}
+};
+
+// SYMMETRIC DISTANCE COMPUTATION (SDC)
+// compares two stored codes without either raw vector. a code stands for x = c + r, its centroid plus
+// one sub-centroid per segment, so every inner product between two decoded vectors splits into
+//     <x, y> = <c_x, c_y> + <c_x, r_y> + <r_x, c_y> + <r_x, r_y>
+// and each term is a sum of inner products computed once at training time:
+//     centroids      <c_a, c_b>                        nlist x nlist
+//     cross          <segment j of c_a, s_j[code]>      nlist x m x ks
+//     sub_centroids  <s_j[code], s_j[code']>            m x ks x ks
+// squared distances come from ||x||^2 + ||y||^2 - 2 <x, y>, so every metric gets the exact distance
+// between the two decoded vectors out of lookups only
+// the tables are stored one list and one subspace at a time, see ListTables
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SdcTables {
+    centroids: Vec<Vec<f64>>,
+    cross: Vec<Vec<Vec<f64>>>,
+    sub_centroids: Vec<Vec<Vec<f64>>>
+}
+
+/// the rows of the tables that belong to list a
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ListTables {
+    /// <c_a, c_b> for every list b
+    pub centroids: Vec<f64>,
+    /// <segment j of c_a, s_j[code]>, m x ks
+    pub cross: Vec<Vec<f64>>
+}
+
+impl SdcTables {
+    pub fn build(codebook: &Codebook, pq_codebook: &PqCodebook) -> Self {
+        let dot = |a: Vec<f64>, b: Vec<f64>| Array1::from(a).dot(&Array1::from(b));
+        let centroids = codebook.iter()
+            .map(|a| codebook.iter().map(|b| dot(a.to_vec(), b.to_vec())).collect())
+            .collect();
+        let cross = codebook.iter()
+            .map(|c| c.into_segments()
+                .enumerate()
+                .map(|(j, seg)| pq_codebook.subspace(j).iter().map(|s| dot(seg.to_vec(), s.to_vec())).collect())
+                .collect())
+            .collect();
+        let sub_centroids = (0..pq_codebook.m())
+            .map(|j| pq_codebook.subspace(j).iter()
+                .map(|a| pq_codebook.subspace(j).iter().map(|b| dot(a.to_vec(), b.to_vec())).collect())
+                .collect())
+            .collect();
+        Self { centroids, cross, sub_centroids }
+    }
+
+    /// tables put back together from the rows of every list and the sub-centroid products of every subspace
+    pub fn from_parts(lists: Vec<ListTables>, subspaces: Vec<Vec<Vec<f64>>>) -> Self {
+        let (centroids, cross) = lists.into_iter().map(|list| (list.centroids, list.cross)).unzip();
+        Self { centroids, cross, sub_centroids: subspaces }
+    }
+
+    /// number of lists the tables cover (nlist)
+    pub fn nlist(&self) -> usize {
+        self.centroids.len()
+    }
+
+    /// number of subspaces (m)
+    pub fn m(&self) -> usize {
+        self.sub_centroids.len()
+    }
+
+    pub fn list(&self, a: Clusters) -> ListTables {
+        ListTables { centroids: self.centroids[a as usize].clone(), cross: self.cross[a as usize].clone() }
+    }
+
+    /// <s_j[code], s_j[code']> for every pair of sub-centroids of subspace j, ks x ks
+    pub fn subspace(&self, j: usize) -> &[Vec<f64>] {
+        &self.sub_centroids[j]
+    }
+
+    /// <x, y> for the decoded vectors of two codes
+    fn inner_product(&self, a: Clusters, a_code: &PqCode, b: Clusters, b_code: &PqCode) -> f64 {
+        let (a, b) = (a as usize, b as usize);
+        self.centroids[a][b] + a_code.iter()
+            .zip(b_code)
+            .enumerate()
+            .map(|(j, (x, y))| {
+                let (x, y) = (*x as usize, *y as usize);
+                self.cross[a][j][y] + self.cross[b][j][x] + self.sub_centroids[j][x][y]
+            })
+            .sum::<f64>()
+    }
+
+    /// distance under metric between the decoded vectors of two codes, reported the way ADC reports it
+    pub fn distance(&self, a: Clusters, a_code: &PqCode, b: Clusters, b_code: &PqCode, metric: Metric) -> f64 {
+        let (dt, offset) = self.list_table(a, a_code, b, metric);
+        let summed = offset + b_code.iter().enumerate().map(|(j, code)| dt[*code as usize][j]).sum::<f64>();
+        metric.reported(summed)
+    }
+
+    /// the nprobe lists whose centroids are nearest to the decoded vector of a code, nearest first.
+    /// squared L2 for the L2 family and -<x, c> for the inner product family, as the ADC tables rank them
+    pub fn nearest_lists(&self, a: Clusters, a_code: &PqCode, nprobe: usize, metric: Metric) -> Vec<Clusters> {
+        let norm_a = self.inner_product(a, a_code, a, a_code);
+        let mut lists = (0..self.centroids.len())
+            .map(|b| {
+                let dot = self.centroids[a as usize][b] + a_code.iter()
+                    .enumerate()
+                    .map(|(j, x)| self.cross[b][j][*x as usize])
+                    .sum::<f64>();
+                let distance = match metric.is_inner_product() {
+                    true => -dot,
+                    false => norm_a + self.centroids[b][b] - 2. * dot
+                };
+                (b as Clusters, distance)
+            })
+            .collect::<Vec<(Clusters, f64)>>();
+        lists.sort_by(|x, y| x.1.total_cmp(&y.1).then(x.0.cmp(&y.0)));
+        lists.into_iter().take(nprobe).map(|(b, _)| b).collect()
+    }
+
+    /// same layout and units as InvertedIndex::list_distance_table: the distance from the code stored
+    /// in list a to a code of list b is offset plus the table entries that code picks
+    pub fn list_table(&self, a: Clusters, a_code: &PqCode, b: Clusters, metric: Metric) -> (DistanceTable, f64) {
+        let (ai, bi) = (a as usize, b as usize);
+        let ks = self.sub_centroids.first().map(Vec::len).unwrap_or(0);
+        // <x, y> = <c_a, c_b> + sum_j <c_b_j, s_j[a_j]> + sum_j (<c_a_j, s_j[y_j]> + <s_j[a_j], s_j[y_j]>)
+        let fixed = self.centroids[ai][bi] + a_code.iter()
+            .enumerate()
+            .map(|(j, x)| self.cross[bi][j][*x as usize])
+            .sum::<f64>();
+        let per_code = |code: usize, j: usize| self.cross[ai][j][code] + self.sub_centroids[j][a_code[j] as usize][code];
+        if metric.is_inner_product() {
+            let offset = match metric {
+                Metric::Cosine => 1.,
+                _ => 0.
+            };
+            let dt = (0..ks)
+                .map(|code| (0..a_code.len()).map(|j| -per_code(code, j)).collect())
+                .collect();
+            return (dt, offset - fixed);
+        }
+        // ||x||^2 + ||y||^2 - 2 <x, y>, with ||y||^2 = <c_b, c_b> + sum_j (2 <c_b_j, s_j[y_j]> + <s_j[y_j], s_j[y_j]>)
+        let norm_a = self.inner_product(a, a_code, a, a_code);
+        let dt = (0..ks)
+            .map(|code| (0..a_code.len())
+                .map(|j| 2. * self.cross[bi][j][code] + self.sub_centroids[j][code][code] - 2. * per_code(code, j))
+                .collect())
+            .collect();
+        (dt, norm_a + self.centroids[bi][bi] - 2. * fixed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ivfpq::{ivfpq::{IndexParams, decode}, primitive_types::{Embedding, Segment}};
+
+    #[test]
+    fn codes_compare_like_their_decoded_vectors() {
+        let params = IndexParams::new(4, 2, 3, 2).unwrap();
+        let emb = |values: Vec<f64>| Embedding::from_base(Array1::from(values), &params).unwrap();
+        let codebook = vec![emb(vec![0.5, -1., 2., 0.]), emb(vec![4., 3., -2., 1.5])];
+        let pq_codebook = PqCodebook::new(vec![
+            vec![Segment::new(vec![0.1, 0.2]), Segment::new(vec![-0.3, 0.7]), Segment::new(vec![1., -1.])],
+            vec![Segment::new(vec![0., 0.5]), Segment::new(vec![0.9, 0.1]), Segment::new(vec![-0.4, -0.6])]
+        ]);
+        let sdc = SdcTables::build(&codebook, &pq_codebook);
+        let codes = [(0, vec![0, 2]), (0, vec![1, 1]), (1, vec![2, 0]), (1, vec![0, 1])];
+        for metric in [Metric::L2, Metric::SquaredL2, Metric::InnerProduct, Metric::Cosine] {
+            for (a, a_code) in &codes {
+                for (b, b_code) in &codes {
+                    let x = Array1::from(decode(a_code, *a, &codebook, &pq_codebook).unwrap().to_vec());
+                    let y = Array1::from(decode(b_code, *b, &codebook, &pq_codebook).unwrap().to_vec());
+                    let expected = match metric {
+                        // what cosine ADC reports for unit vectors
+                        Metric::Cosine => 1. - x.dot(&y),
+                        _ => metric.distance(x.view(), y.view())
+                    };
+                    let got = sdc.distance(*a, a_code, *b, b_code, metric);
+                    // the square root of L2 magnifies rounding near zero
+                    assert!((got - expected).abs() < 1e-6, "{metric:?} {a}/{a_code:?} {b}/{b_code:?}: {got} vs {expected}");
+                }
+            }
+        }
+        // the list of a code is the nearest one to it, its neighbour right after
+        assert_eq!(sdc.nearest_lists(1, &vec![2, 0], 2, Metric::L2), vec![1, 0]);
+        assert_eq!(sdc.nearest_lists(0, &vec![0, 2], 1, Metric::SquaredL2), vec![0]);
+        // a code is at distance zero from itself
+        assert!(sdc.distance(1, &vec![2, 0], 1, &vec![2, 0], Metric::L2) < 1e-6);
+        // stored by parts
+        let parts = SdcTables::from_parts(vec![sdc.list(0), sdc.list(1)], vec![sdc.subspace(0).to_vec(), sdc.subspace(1).to_vec()]);
+        assert_eq!(parts, sdc);
+    }
+}
@@ -1,16 +1,139 @@
-use serde::{Serialize, Deserialize, de::Visitor};
+use std::io::{ErrorKind, Read, Write};
+use serde::{Serialize, Deserialize, de::Visitor, ser::Error};
 use super::ivfpq::AvlWrapper;
 use super::primitive_types::IVListEntry;
+use super::error::{KathleenError, KathleenResult};
 use avl::AvlTreeMap;
 #[macro_use]
 use log::debug;
 
+// BINARY LIST FORMAT
+// header:  format version (u8) | code words per entry, m (u16 LE)
+// records: vector id (u32 LE) | pq code (m x u8) | cluster (u32 LE)
+// every record has the same width, so entries can be read one at a time
+
+pub const LIST_FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 3;
+
+fn record_len(m: usize) -> usize {
+    4 + m + 4
+}
+
+/// writes the header followed by one record per entry, every code must be m words long
+pub fn write_list<'a, W: Write>(writer: &mut W, m: usize, entries: impl IntoIterator<Item = (u32, &'a IVListEntry)>) -> KathleenResult<()> {
+    let code_len = u16::try_from(m)
+        .map_err(|_| KathleenError::Serialization(format!("{m} code words don't fit the list header")))?;
+    let mut header = vec![LIST_FORMAT_VERSION];
+    header.extend(code_len.to_le_bytes());
+    writer.write_all(&header).map_err(|e| KathleenError::Serialization(e.to_string()))?;
+    for (id, entry) in entries {
+        if entry.get_code().len() != m {
+            return Err(KathleenError::Serialization(format!("entry {id} has {} code words, expected {m}", entry.get_code().len())));
+        }
+        let mut record = Vec::with_capacity(record_len(m));
+        record.extend(id.to_le_bytes());
+        record.extend(entry.get_code());
+        record.extend(entry.get_cluster().to_le_bytes());
+        writer.write_all(&record).map_err(|e| KathleenError::Serialization(e.to_string()))?;
+    }
+    Ok(())
+}
+
+/// whole list in the binary format, m is taken from its first entry
+pub fn encode_list(source: &AvlTreeMap<u32, Box<IVListEntry>>) -> KathleenResult<Vec<u8>> {
+    let m = source.iter().next().map_or(0, |(_, entry)| entry.get_code().len());
+    let mut bytes = Vec::with_capacity(HEADER_LEN + source.len() * record_len(m));
+    write_list(&mut bytes, m, source.iter().map(|(id, entry)| (*id, entry.as_ref())))?;
+    Ok(bytes)
+}
+
+/// single entry as a list of one, this is what each column family value holds
+pub fn encode_entry(id: u32, entry: &IVListEntry) -> KathleenResult<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + record_len(entry.get_code().len()));
+    write_list(&mut bytes, entry.get_code().len(), [(id, entry)])?;
+    Ok(bytes)
+}
+
+pub fn decode_entry(bytes: &[u8]) -> KathleenResult<(u32, IVListEntry)> {
+    let mut reader = ListReader::new(bytes)?;
+    let entry = reader.next()
+        .unwrap_or_else(|| Err(KathleenError::Parse("no entry in record".to_string())))?;
+    match reader.next() {
+        None => Ok(entry),
+        Some(_) => Err(KathleenError::Parse("more than one entry in record".to_string()))
+    }
+}
+
+/// streams (id, entry) records out of a binary list, the header is checked on creation
+pub struct ListReader<R> {
+    reader: R,
+    m: usize
+}
+
+impl<R: Read> ListReader<R> {
+    pub fn new(mut reader: R) -> KathleenResult<Self> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)
+            .map_err(|e| KathleenError::Parse(format!("missing list header: {e}")))?;
+        if header[0] != LIST_FORMAT_VERSION {
+            return Err(KathleenError::Parse(format!("unsupported list format version {}", header[0])));
+        }
+        Ok(Self { reader, m: u16::from_le_bytes([header[1], header[2]]) as usize })
+    }
+
+    pub fn m(&self) -> usize {
+        self.m
+    }
+
+    fn read_record(&mut self) -> KathleenResult<Option<Vec<u8>>> {
+        let mut record = vec![0; record_len(self.m)];
+        let mut filled = 0;
+        while filled < record.len() {
+            match self.reader.read(&mut record[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(KathleenError::Parse(e.to_string()))
+            }
+        }
+        match filled {
+            0 => Ok(None),
+            n if n == record.len() => Ok(Some(record)),
+            n => Err(KathleenError::Parse(format!("truncated record, {n} of {} bytes", record.len())))
+        }
+    }
+}
+
+impl<R: Read> Iterator for ListReader<R> {
+    type Item = KathleenResult<(u32, IVListEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.read_record() {
+            Ok(record) => record?,
+            Err(e) => return Some(Err(e))
+        };
+        let m = self.m;
+        let id = u32::from_le_bytes(record[..4].try_into().unwrap());
+        let cluster = u32::from_le_bytes(record[4 + m..].try_into().unwrap());
+        Some(Ok((id, IVListEntry::new(record[4..4 + m].to_vec(), cluster))))
+    }
+}
+
+fn decode_list(bytes: &[u8]) -> KathleenResult<AvlTreeMap<u32, Box<IVListEntry>>> {
+    let mut avl = AvlTreeMap::new();
+    for record in ListReader::new(bytes)? {
+        let (id, entry) = record?;
+        avl.insert(id, Box::new(entry));
+    }
+    Ok(avl)
+}
+
 impl Serialize for AvlWrapper {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: serde::Serializer {
         
-        serializer.serialize_str(&to_json(&self))
+        serializer.serialize_bytes(&encode_list(self).map_err(S::Error::custom)?)
     }
 }
 
@@ -26,29 +149,25 @@ impl<'de> Deserialize<'de> for AvlWrapper {
                         formatter.write_str("struct AvlWrapper")
                     }
 
-                    fn visit_string<E>(self, v: String) -> Result<AvlWrapper, E>
+                    fn visit_bytes<E>(self, v: &[u8]) -> Result<AvlWrapper, E>
                         where
                             E: serde::de::Error, {
-                        Ok(AvlWrapper::from(from_json(v)))
+                        decode_list(v).map(AvlWrapper::from).map_err(E::custom)
                     }
 
+                    // lists written before the binary format
                     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
                         where
                             E: serde::de::Error, {
-                                Ok(AvlWrapper::from(from_json(v.to_string())))
-                    }
-
-                    fn visit_bytes<E>(self, v: &[u8]) -> Result<AvlWrapper, E>
-                        where
-                            E: serde::de::Error, {
-                        let json_avl_wrapper: String = serde_cbor::from_slice(v).expect("Error deserializing in visitor");
-                        Ok(AvlWrapper::from(from_json(json_avl_wrapper)))
+                                from_json(v.to_string()).map(AvlWrapper::from).map_err(E::custom)
                     }
                 }
-                deserializer.deserialize_str(AvlVisitor)
+                deserializer.deserialize_bytes(AvlVisitor)
     }
 }
 
+// string format lists were stored in before the binary one, nothing writes it anymore
+#[cfg(test)]
 fn to_json(source: &AvlTreeMap<u32, Box<IVListEntry>>) -> String {
     "{".to_string() + 
     &source
@@ -58,34 +177,36 @@ fn to_json(source: &AvlTreeMap<u32, Box<IVListEntry>>) -> String {
     "}"
 }
 
-fn from_json(mut source: String) -> AvlTreeMap<u32, Box<IVListEntry>> {
-    source.remove(0);
-    source.remove(source.len()-1);
-    source.remove(source.len()-1);
-    let splitted = source.split('\n');
+fn from_json(source: String) -> KathleenResult<AvlTreeMap<u32, Box<IVListEntry>>> {
+    let inner = source
+        .strip_prefix('{')
+        .and_then(|src| src.strip_suffix('}'))
+        .ok_or_else(|| KathleenError::Parse("inverted list is not wrapped in braces".to_string()))?;
     let mut avl = AvlTreeMap::new();
-    splitted
-        .for_each(|line| {
-            let splitted = line.split(':').collect::<Vec<&str>>();
-            let key = splitted[0];
-            let value = splitted[1];
-            avl.insert(key.parse::<u32>().unwrap(), Box::new(IVListEntry::from_str(value)));
-        });
-    avl
+    // every entry is followed by a newline, an empty list has none
+    for line in inner.split('\n').filter(|line| !line.is_empty()) {
+        let (key, value) = line.split_once(':')
+            .ok_or_else(|| KathleenError::Parse(format!("malformed inverted list line {line:?}")))?;
+        let key = key.parse::<u32>()
+            .map_err(|e| KathleenError::Parse(format!("invalid vector id {key:?}: {e}")))?;
+        avl.insert(key, Box::new(IVListEntry::from_str(value)?));
+    }
+    Ok(avl)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::ivfpq::ivfpq::{CODE_SIZE, InvertedIndex, AvlWrapper, EMBEDDING_M_SEGMENTS};
+    use crate::ivfpq::{ivfpq::{InvertedIndex, AvlWrapper}, test_utils::test_params};
 
     use super::*;
 
     #[test]
     fn serialization_works() {
+        let params = test_params();
         let mut avl = AvlWrapper::new();
-        avl.insert(123, Box::new(IVListEntry::new([1; EMBEDDING_M_SEGMENTS], 0)));
-        avl.insert(124, Box::new(IVListEntry::new([1; EMBEDDING_M_SEGMENTS], 1)));
-        let mut ivf = InvertedIndex::empty();
+        avl.insert(123, Box::new(IVListEntry::new(vec![1; params.m()], 0)));
+        avl.insert(124, Box::new(IVListEntry::new(vec![1; params.m()], 1)));
+        let mut ivf = InvertedIndex::empty(params);
         ivf.push(avl);
         let bytes = serde_cbor::to_vec(&ivf).unwrap();
         let des_ivf: InvertedIndex = serde_cbor::from_slice(&bytes).unwrap();
@@ -98,14 +219,66 @@ mod tests {
     #[test]
     fn avl_tree_map_serialization_works() {
         let mut avl = AvlWrapper::new();
-        avl.insert(132, Box::new(IVListEntry::new([1; EMBEDDING_M_SEGMENTS], 0)));
-        avl.insert(132, Box::new(IVListEntry::new([2; EMBEDDING_M_SEGMENTS], 1)));
+        avl.insert(132, Box::new(IVListEntry::new(vec![1; 4], 0)));
+        avl.insert(132, Box::new(IVListEntry::new(vec![2; 4], 1)));
         let curr_avl = avl.clone();
         
         let avl_bytes = to_json(&avl);
-        let des_avl = from_json(avl_bytes);
+        let des_avl = from_json(avl_bytes).unwrap();
         
         assert_eq!(to_json(&curr_avl), to_json(&des_avl));
     }
 
+    #[test]
+    fn binary_lists_round_trip() {
+        let mut avl = AvlWrapper::new();
+        avl.insert(7, Box::new(IVListEntry::new(vec![0, 255, 3, 9], 2)));
+        avl.insert(u32::MAX, Box::new(IVListEntry::new(vec![1, 2, 3, 4], 7)));
+        let bytes = encode_list(&avl).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + 2 * record_len(4));
+        assert!(bytes.len() < to_json(&avl).len());
+        assert_eq!(to_json(&decode_list(&bytes).unwrap()), to_json(&avl));
+        assert_eq!(decode_list(&encode_list(&AvlWrapper::new()).unwrap()).unwrap().len(), 0);
+
+        let entry = IVListEntry::new(vec![5; 4], 1);
+        assert_eq!(decode_entry(&encode_entry(3, &entry).unwrap()).unwrap(), (3, entry));
+        assert!(decode_entry(&bytes).is_err());
+    }
+
+    #[test]
+    fn list_reader_streams_entries() {
+        let entries = (0..3).map(|id| IVListEntry::new(vec![id as u8; 4], id)).collect::<Vec<_>>();
+        let mut bytes = Vec::new();
+        write_list(&mut bytes, 4, entries.iter().enumerate().map(|(id, entry)| (id as u32, entry))).unwrap();
+        // a cut off tail only fails once it's reached
+        bytes.truncate(bytes.len() - 1);
+        let mut reader = ListReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.m(), 4);
+        assert_eq!(reader.next().unwrap().unwrap(), (0, entries[0].clone()));
+        assert_eq!(reader.next().unwrap().unwrap(), (1, entries[1].clone()));
+        assert!(matches!(reader.next(), Some(Err(KathleenError::Parse(_)))));
+
+        assert!(ListReader::new([LIST_FORMAT_VERSION + 1, 4, 0].as_slice()).is_err());
+        assert!(ListReader::new([LIST_FORMAT_VERSION].as_slice()).is_err());
+        assert!(write_list(&mut Vec::new(), 3, [(0, &entries[0])]).is_err());
+    }
+
+    #[test]
+    fn string_lists_are_still_read() {
+        let mut avl = AvlWrapper::new();
+        avl.insert(12, Box::new(IVListEntry::new(vec![1, 2, 3, 4], 0)));
+        let legacy = serde_cbor::to_vec(&to_json(&avl)).unwrap();
+        let des_avl: AvlWrapper = serde_cbor::from_slice(&legacy).unwrap();
+        assert_eq!(to_json(&des_avl), to_json(&avl));
+    }
+
+    #[test]
+    fn empty_and_malformed_lists() {
+        let empty = from_json(to_json(&AvlWrapper::new())).unwrap();
+        assert_eq!(empty.len(), 0);
+        assert!(from_json("".to_string()).is_err());
+        assert!(from_json("{123 [1, 1, 1, 1];0\n}".to_string()).is_err());
+        assert!(from_json("{abc: [1, 1, 1, 1];0\n}".to_string()).is_err());
+    }
+
 }
@@ -0,0 +1,54 @@
+use std::path::Path;
+use super::{
+    db_api::{DatabaseWrapper, Open},
+    ivfpq::IndexParams,
+    primitive_types::{Codebook, Embedding, PqCodebook}
+};
+
+// TEST UTILS
+// geometry, embeddings and databases the tests of every module start from
+
+/// 12 dimensions in 4 subspaces of 8 sub-centroids over 8 lists, what the embeddings under tests/ are shaped for
+pub(crate) fn test_params() -> IndexParams {
+    IndexParams::new(12, 4, 8, 8).unwrap()
+}
+
+/// one embedding per line of the file at path
+pub(crate) fn read_embeddings(path: &str, params: &IndexParams) -> Vec<Embedding> {
+    std::fs::read_to_string(path).unwrap()
+        .lines()
+        .map(|emb| Embedding::read_from_str(emb, params).unwrap())
+        .collect()
+}
+
+/// EMBEDDINGS_PER_CLUSTER embeddings around each of nlist points, what the test indexes get trained on
+pub(crate) fn training_embeddings(params: &IndexParams) -> Vec<Embedding> {
+    read_embeddings("tests/k_means_test_embs", params)
+}
+
+/// a repo close to the training embeddings around 40
+pub(crate) fn repo_embedding(params: &IndexParams) -> Embedding {
+    Embedding::read_from_str("[40.3, 40.1, 40.7, 40.2, 40.9, 40.4, 40., 40.6, 40.2, 40.8, 40.1, 40.5]", params).unwrap()
+}
+
+/// uses the segments of every embedding as the sub-centroids of each subspace
+pub(crate) fn pq_codebook_from(cb: &Codebook) -> PqCodebook {
+    let m = cb[0].into_segments().len();
+    PqCodebook::new(
+        (0..m).map(|j| cb.iter().map(|emb| emb.into_segments().nth(j).unwrap().clone()).collect()).collect()
+    )
+}
+
+/// path, with whatever a previous run left there destroyed
+pub(crate) fn fresh_db(path: &str) -> &Path {
+    let path = Path::new(path);
+    rocksdb::DB::destroy(&rocksdb::Options::default(), path).unwrap();
+    path
+}
+
+/// a new database under path with the test params, the path and params come back for reopening it
+pub(crate) fn fresh_database(path: &str) -> (DatabaseWrapper<Open>, &Path, IndexParams) {
+    let (path, params) = (fresh_db(path), test_params());
+    let db = DatabaseWrapper::open(path, &params).expect("Opening failed: ");
+    (db, path, params)
+}
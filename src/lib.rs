@@ -1,4 +1,4 @@
-mod ivfpq;
+pub mod ivfpq;
 
 
 